@@ -1,7 +1,9 @@
+use std::path::PathBuf;
 use std::u32;
 
 use clap::Parser;
-use vmm::VMM;
+use log::{LevelFilter, Log, Metadata, Record};
+use vmm::{HugePageSize, VMM};
 
 #[derive(Parser)]
 #[clap(version = "0.1", author = "Polytech Montpellier - DevOps")]
@@ -22,44 +24,118 @@ struct VMMOpts {
     #[clap(short, long, default_value = "512")]
     memory: u32,
 
-    /// A level of verbosity, and can be used multiple times
+    /// A level of verbosity, and can be used multiple times: -v logs the boot sequence, -vv and
+    /// -vvv add debug and trace messages
     #[clap(short, long, action=clap::ArgAction::Count )]
     verbose: u8,
 
     /// Stdout console file path
     #[clap(long)]
     console: Option<String>,
+
+    /// Back the guest memory with huge pages of the given size (2M or 1G)
+    #[clap(long)]
+    hugepages: Option<HugePageSize>,
+
+    /// Map the guest memory from this file, shared, instead of anonymous memory
+    #[clap(long)]
+    mem_file: Option<PathBuf>,
+
+    /// Keep the guest halted on kernel panic instead of rebooting it
+    #[clap(long)]
+    panic_halt: bool,
+
+    /// Listen for GDB connections on this address (guest memory access only)
+    #[clap(long)]
+    gdb: Option<String>,
+
+    /// Context identifier of the guest, e.g. for vsock (3 or more)
+    #[clap(long)]
+    guest_cid: Option<u32>,
+}
+
+// Prints the log records to stderr, keeping stdout for the guest console.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
 }
 
+static LOGGER: StderrLogger = StderrLogger;
+
 #[derive(Debug)]
 pub enum Error {
+    Logger(log::SetLoggerError),
+
     VmmNew(vmm::Error),
 
     VmmConfigure(vmm::Error),
 
+    VmmGdb(vmm::Error),
+
     VmmRun(vmm::Error),
 }
 
 fn main() -> Result<(), Error> {
     let opts: VMMOpts = VMMOpts::parse();
 
+    log::set_logger(&LOGGER).map_err(Error::Logger)?;
+    log::set_max_level(match opts.verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+
     // Create a new VMM
     let mut vmm = VMM::new().map_err(Error::VmmNew)?;
 
+    if opts.panic_halt {
+        vmm.on_panic_halt();
+    }
+    if let Some(mem_file) = opts.mem_file {
+        vmm.set_memory_file(mem_file);
+    }
+    if let Some(guest_cid) = opts.guest_cid {
+        vmm.set_guest_cid(guest_cid).map_err(Error::VmmConfigure)?;
+    }
+
     // Configure the VMM:
     // * Number of virtual CPUs
     // * Memory size (in MB)
     // * Path to a Linux kernel
     // * Optional path to console file
+    // * Optional huge page size backing the guest memory
     vmm.configure(
         opts.cpus,
         opts.memory,
         &opts.kernel,
         opts.console,
         opts.initramfs,
+        opts.hugepages,
     )
     .map_err(Error::VmmConfigure)?;
 
+    // Logged rather than printed, stdout is the guest console.
+    if log::log_enabled!(log::Level::Info) {
+        let memory_map = vmm.memory_map().map_err(Error::VmmConfigure)?;
+        log::info!("Guest memory map:\n{}", memory_map);
+    }
+
+    if let Some(gdb) = opts.gdb {
+        vmm.attach_gdb(gdb).map_err(Error::VmmGdb)?;
+    }
+
     // Run the VMM
     vmm.run().map_err(Error::VmmRun)?;
 
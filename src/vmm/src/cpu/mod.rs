@@ -2,18 +2,18 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::convert::TryInto;
-use std::io;
 use std::sync::{Arc, Mutex};
 use std::{result, u64};
 
-use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs, CpuId};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
 use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
-use vmm_sys_util::terminal::Terminal;
 
+use crate::devices::i8042::{LumperI8042, I8042_COMMAND_PORT, I8042_DATA_PORT};
 use crate::devices::serial::{LumperSerial, SERIAL_PORT_BASE, SERIAL_PORT_LAST_REGISTER};
+use crate::VmExit;
 
 pub(crate) mod cpuid;
 mod gdt;
@@ -33,6 +33,10 @@ const PML4_START: u64 = 0x9000;
 const PDPTE_START: u64 = 0xa000;
 const PDE_START: u64 = 0xb000;
 
+// Code segment flags, for 64-bit and for 32-bit code.
+const CODE_SEGMENT_64: u16 = 0xa09b;
+const CODE_SEGMENT_32: u16 = 0xc09b;
+
 const X86_CR0_PE: u64 = 0x1;
 const X86_CR0_PG: u64 = 0x8000_0000;
 const X86_CR4_PAE: u64 = 0x20;
@@ -57,6 +61,45 @@ pub enum Error {
 /// Dedicated Result type.
 pub type Result<T> = result::Result<T, Error>;
 
+// Write flat code, data and TSS segments to the GDT in guest memory, with an empty IDT, and load
+// them in `sregs`. `code_flags` tells whether the code segment is for 64-bit or 32-bit code.
+fn configure_segments(
+    sregs: &mut kvm_sregs,
+    code_flags: u16,
+    guest_memory: &GuestMemoryMmap,
+) -> Result<()> {
+    // Global descriptor tables.
+    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
+        gdt_entry(0, 0, 0),                // NULL
+        gdt_entry(code_flags, 0, 0xfffff), // CODE
+        gdt_entry(0xc093, 0, 0xfffff),     // DATA
+        gdt_entry(0x808b, 0, 0xfffff),     // TSS
+    ];
+
+    let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
+    let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
+    let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
+
+    // Write segments to guest memory.
+    write_gdt_table(&gdt_table[..], guest_memory).map_err(Error::GuestMemory)?;
+    sregs.gdt.base = BOOT_GDT_OFFSET as u64;
+    sregs.gdt.limit = std::mem::size_of_val(&gdt_table) as u16 - 1;
+
+    write_idt_value(0, guest_memory).map_err(Error::GuestMemory)?;
+    sregs.idt.base = BOOT_IDT_OFFSET as u64;
+    sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.tr = tss_seg;
+
+    Ok(())
+}
+
 /// Struct for interacting with vCPUs.
 ///
 /// This struct is a temporary (and quite terrible) placeholder until the
@@ -68,6 +111,7 @@ pub(crate) struct Vcpu {
     pub vcpu_fd: VcpuFd,
 
     serial: Arc<Mutex<LumperSerial>>,
+    i8042: Arc<Mutex<LumperI8042>>,
     virtio_manager: Arc<Mutex<IoManager>>,
 }
 
@@ -77,12 +121,14 @@ impl Vcpu {
         vm_fd: &VmFd,
         index: u64,
         serial: Arc<Mutex<LumperSerial>>,
+        i8042: Arc<Mutex<LumperI8042>>,
         virtio_manager: Arc<Mutex<IoManager>>,
     ) -> Result<Self> {
         Ok(Vcpu {
             index,
             vcpu_fd: vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?,
             serial,
+            i8042,
             virtio_manager,
         })
     }
@@ -125,38 +171,29 @@ impl Vcpu {
         self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
     }
 
+    /// Configure regs for the Multiboot2 protocol: the bootloader magic in eax, and the address
+    /// of the boot information in ebx.
+    pub fn configure_multiboot_regs(
+        &self,
+        entry: GuestAddress,
+        boot_info: GuestAddress,
+    ) -> Result<()> {
+        let regs = kvm_regs {
+            rflags: 0x0000_0000_0000_0002u64,
+            rip: entry.raw_value(),
+            rax: u64::from(crate::multiboot::BOOTLOADER_MAGIC),
+            rbx: boot_info.raw_value(),
+            // The kernel sets its own stack up, but give it a valid one anyway.
+            rsp: BOOT_STACK_POINTER,
+            ..Default::default()
+        };
+        self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
+    }
+
     /// Configure sregs.
     pub fn configure_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
         let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
-
-        // Global descriptor tables.
-        let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-            gdt_entry(0, 0, 0),            // NULL
-            gdt_entry(0xa09b, 0, 0xfffff), // CODE
-            gdt_entry(0xc093, 0, 0xfffff), // DATA
-            gdt_entry(0x808b, 0, 0xfffff), // TSS
-        ];
-
-        let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
-        let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
-        let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
-
-        // Write segments to guest memory.
-        write_gdt_table(&gdt_table[..], guest_memory).map_err(Error::GuestMemory)?;
-        sregs.gdt.base = BOOT_GDT_OFFSET as u64;
-        sregs.gdt.limit = std::mem::size_of_val(&gdt_table) as u16 - 1;
-
-        write_idt_value(0, guest_memory).map_err(Error::GuestMemory)?;
-        sregs.idt.base = BOOT_IDT_OFFSET as u64;
-        sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
-
-        sregs.cs = code_seg;
-        sregs.ds = data_seg;
-        sregs.es = data_seg;
-        sregs.fs = data_seg;
-        sregs.gs = data_seg;
-        sregs.ss = data_seg;
-        sregs.tr = tss_seg;
+        configure_segments(&mut sregs, CODE_SEGMENT_64, guest_memory)?;
 
         // 64-bit protected mode.
         sregs.cr0 |= X86_CR0_PE;
@@ -193,6 +230,19 @@ impl Vcpu {
         self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl)
     }
 
+    /// Configure sregs for the Multiboot2 protocol: 32-bit protected mode with flat segments,
+    /// without paging.
+    pub fn configure_multiboot_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
+        configure_segments(&mut sregs, CODE_SEGMENT_32, guest_memory)?;
+
+        sregs.cr0 |= X86_CR0_PE;
+        sregs.cr0 &= !X86_CR0_PG;
+        sregs.efer &= !((msr_index::EFER_LME | msr_index::EFER_LMA) as u64);
+
+        self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl)
+    }
+
     /// Configure FPU.
     pub fn configure_fpu(&self) -> Result<()> {
         let fpu = kvm_fpu {
@@ -223,22 +273,18 @@ impl Vcpu {
         self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
     }
 
-    /// vCPU emulation loop.
-    pub fn run(&mut self) {
+    /// vCPU emulation loop. Returns why the guest stopped, if it did.
+    pub fn run(&mut self) -> Option<VmExit> {
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
         match self.vcpu_fd.run() {
             Ok(exit_reason) => match exit_reason {
-                // The VM stopped (Shutdown ot HLT).
-                VcpuExit::Shutdown | VcpuExit::Hlt => {
-                    println!("Guest shutdown: {:?}. Bye!", exit_reason);
-                    let stdin = io::stdin();
-                    let stdin_lock = stdin.lock();
-                    stdin_lock.set_canon_mode().unwrap();
-
-                    unsafe { libc::exit(0) };
-                }
+                // A triple fault resets the CPU, which is also how Linux ends up rebooting when
+                // nothing else worked.
+                VcpuExit::Shutdown => return Some(VmExit::Reboot),
+                // With the in-kernel irqchip, a HLT only exits once the vCPU can't be woken up.
+                VcpuExit::Hlt => return Some(VmExit::Shutdown),
 
                 // This is a PIO write, i.e. the guest is trying to write
                 // something to an I/O port.
@@ -256,6 +302,16 @@ impl Vcpu {
                             )
                             .unwrap();
                     }
+                    I8042_DATA_PORT | I8042_COMMAND_PORT => {
+                        if let Err(e) = self
+                            .i8042
+                            .lock()
+                            .unwrap()
+                            .write((addr - I8042_DATA_PORT) as u8, data[0])
+                        {
+                            eprintln!("Failed to signal the i8042 reset: {:?}", e);
+                        }
+                    }
                     _ => {
                         println!("Unsupported device write at {:x?}", addr);
                     }
@@ -271,6 +327,13 @@ impl Vcpu {
                                 .expect("Invalid serial register offset"),
                         );
                     }
+                    I8042_DATA_PORT | I8042_COMMAND_PORT => {
+                        data[0] = self
+                            .i8042
+                            .lock()
+                            .unwrap()
+                            .read((addr - I8042_DATA_PORT) as u8);
+                    }
                     _ => {
                         println!("Unsupported device read at {:x?}", addr);
                     }
@@ -303,5 +366,7 @@ impl Vcpu {
 
             Err(e) => eprintln!("Emulation error: {}", e),
         }
+
+        None
     }
 }
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use vm_superio::I8042Device;
+
+use super::serial::EventFdTrigger;
+
+/// i8042 data port.
+pub const I8042_DATA_PORT: u16 = 0x60;
+/// i8042 command and status port.
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+
+/// Minimal i8042 controller. It only implements the CPU reset command (0xfe), used by guests
+/// booted with `reboot=k`, which signals the reset eventfd. The keyboard probe finds no device.
+pub(crate) type LumperI8042 = I8042Device<EventFdTrigger>;
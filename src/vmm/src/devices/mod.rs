@@ -1,4 +1,5 @@
 // SPDX-License-Identifier: Apache-2.0
 
+pub(crate) mod i8042;
 pub(crate) mod net;
 pub(crate) mod serial;
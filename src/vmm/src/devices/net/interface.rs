@@ -5,9 +5,23 @@ use std::{
 
 use super::Result;
 
+/// Frames exchanged with the host interface.
+///
+/// virtio-net guests always send and expect Ethernet frames, so `Tun` doesn't fit a virtio-net
+/// device as is: it is meant for a layer 3 device variant, or for raw forwarding of IP packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkLayer {
+    /// Ethernet frames (`IFF_TAP`).
+    #[default]
+    Tap,
+    /// IP packets without an Ethernet header (`IFF_TUN`), for point-to-point setups.
+    #[allow(dead_code)]
+    Tun,
+}
+
 pub trait Interface: Read + Write + AsRawFd + Send + Sync {
     fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> Result<()>;
-    fn open_named(if_name: &str) -> Result<Self>
+    fn open_named(if_name: &str, link_layer: LinkLayer) -> Result<Self>
     where
         Self: Sized;
 }
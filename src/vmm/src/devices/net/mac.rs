@@ -0,0 +1,158 @@
+use std::{error::Error, fmt::Display};
+
+/// Number of octets in a MAC address.
+pub const MAC_ADDR_LEN: usize = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MacParseError {
+    /// The address doesn't have exactly `MAC_ADDR_LEN` octets.
+    InvalidLength(usize),
+    /// An octet isn't made of two hexadecimal digits.
+    InvalidOctet(String),
+    /// Both `:` and `-` are used as separators.
+    MixedSeparators,
+    /// The multicast bit is set on an address that must be unicast.
+    Multicast,
+}
+impl Error for MacParseError {}
+impl Display for MacParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MacParseError::InvalidLength(len) => {
+                write!(f, "expected {} octets, found {}", MAC_ADDR_LEN, len)
+            }
+            MacParseError::InvalidOctet(octet) => write!(f, "invalid octet {:?}", octet),
+            MacParseError::MixedSeparators => write!(f, "mixed ':' and '-' separators"),
+            MacParseError::Multicast => write!(f, "multicast address where unicast is required"),
+        }
+    }
+}
+
+/// Parse a MAC address written as `aa:bb:cc:dd:ee:ff` or `aa-bb-cc-dd-ee-ff`.
+#[allow(dead_code)]
+pub fn parse_mac(s: &str) -> Result<[u8; MAC_ADDR_LEN], MacParseError> {
+    let separator = match (s.contains(':'), s.contains('-')) {
+        (true, true) => return Err(MacParseError::MixedSeparators),
+        (false, true) => '-',
+        _ => ':',
+    };
+
+    let octets: Vec<&str> = s.split(separator).collect();
+    if octets.len() != MAC_ADDR_LEN {
+        return Err(MacParseError::InvalidLength(octets.len()));
+    }
+
+    let mut mac = [0u8; MAC_ADDR_LEN];
+    for (byte, octet) in mac.iter_mut().zip(octets) {
+        // `from_str_radix` alone would accept a leading `+` or a single digit.
+        if octet.len() != 2 || !octet.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(MacParseError::InvalidOctet(octet.to_string()));
+        }
+        *byte = u8::from_str_radix(octet, 16)
+            .map_err(|_| MacParseError::InvalidOctet(octet.to_string()))?;
+    }
+
+    Ok(mac)
+}
+
+/// Parse a MAC address like [`parse_mac`], rejecting multicast addresses.
+#[allow(dead_code)]
+pub fn parse_unicast_mac(s: &str) -> Result<[u8; MAC_ADDR_LEN], MacParseError> {
+    let mac = parse_mac(s)?;
+    if mac[0] & 0x01 != 0 {
+        return Err(MacParseError::Multicast);
+    }
+
+    Ok(mac)
+}
+
+/// Derive a stable MAC address from a VM identifier.
+///
+/// The identifier is hashed with 64-bit FNV-1a, which doesn't depend on the Rust version like
+/// `DefaultHasher` does. The result is a locally administered unicast address.
+#[allow(dead_code)]
+pub fn mac_from_id(vm_id: &str) -> [u8; MAC_ADDR_LEN] {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let hash = vm_id.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    });
+
+    let mut mac = [0u8; MAC_ADDR_LEN];
+    mac.copy_from_slice(&hash.to_le_bytes()[..MAC_ADDR_LEN]);
+    // Set the locally administered bit, clear the multicast one.
+    mac[0] = (mac[0] | 0x02) & !0x01;
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_macs() {
+        assert_eq!(
+            parse_mac("0a:1B:2c:3D:4e:5F"),
+            Ok([0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f])
+        );
+        assert_eq!(
+            parse_mac("0a-1b-2c-3d-4e-5f"),
+            Ok([0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f])
+        );
+    }
+
+    #[test]
+    fn parse_invalid_macs() {
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e"),
+            Err(MacParseError::InvalidLength(5))
+        );
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e:5f:60"),
+            Err(MacParseError::InvalidLength(7))
+        );
+        assert_eq!(parse_mac(""), Err(MacParseError::InvalidLength(1)));
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e:5g"),
+            Err(MacParseError::InvalidOctet("5g".to_string()))
+        );
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e:f"),
+            Err(MacParseError::InvalidOctet("f".to_string()))
+        );
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e:+f"),
+            Err(MacParseError::InvalidOctet("+f".to_string()))
+        );
+        assert_eq!(
+            parse_mac("0a:1b:2c-3d:4e:5f"),
+            Err(MacParseError::MixedSeparators)
+        );
+    }
+
+    #[test]
+    fn parse_unicast_rejects_multicast() {
+        assert_eq!(
+            parse_unicast_mac("02:00:00:00:00:01"),
+            Ok([0x02, 0, 0, 0, 0, 0x01])
+        );
+        assert_eq!(
+            parse_unicast_mac("01:00:5e:00:00:01"),
+            Err(MacParseError::Multicast)
+        );
+    }
+
+    #[test]
+    fn mac_from_id_is_stable_local_unicast() {
+        let mac = mac_from_id("vm-0");
+        assert_eq!(mac, mac_from_id("vm-0"));
+        assert_ne!(mac, mac_from_id("vm-1"));
+
+        for id in ["", "vm-0", "vm-1", "a-much-longer-vm-identifier"] {
+            let mac = mac_from_id(id);
+            assert_eq!(mac[0] & 0x03, 0x02, "{:x?}", mac);
+        }
+    }
+}
@@ -2,12 +2,20 @@ use std::{error::Error, fmt::Display};
 
 pub mod bindings;
 pub mod interface;
+pub mod mac;
 pub mod tap;
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum VirtioNetError {
     InvalidIfname,
+    VnetHdrNotSupported,
+    BridgeNotFound(String),
+    BridgeAttach(String, std::io::Error),
+    InvalidPrefix(u8),
+    NetnsOpen(std::path::PathBuf, std::io::Error),
+    NetnsEnter(std::io::Error),
+    NetnsRestore(std::io::Error),
     VirtioQueueError(virtio_queue::Error),
     IoCtlError(std::io::Error),
     IoError(std::io::Error),
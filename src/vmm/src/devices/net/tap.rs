@@ -10,15 +10,18 @@
 
 use std::fs::File;
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
+use std::net::Ipv4Addr;
 use std::os::raw::{c_char, c_uint, c_ulong};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use virtio_bindings::bindings::virtio_net::{VIRTIO_NET_F_CSUM, VIRTIO_NET_F_HOST_UFO};
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
-use vmm_sys_util::{ioctl_ioc_nr, ioctl_iow_nr};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
 
-use super::bindings::{ifreq, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO};
-use super::interface::Interface;
+use super::bindings::{ifreq, sockaddr, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO};
+use super::interface::{Interface, LinkLayer};
 use super::VirtioNetError;
 
 // As defined in the Linux UAPI:
@@ -27,15 +30,27 @@ const IFACE_NAME_MAX_LEN: usize = 16;
 
 // Taken from firecracker net_gen/if_tun.rs ... we should see what to do about the net related
 // bindings overall for rust-vmm.
+const IFF_TUN: ::std::os::raw::c_uint = 1;
 const IFF_TAP: ::std::os::raw::c_uint = 2;
 const IFF_NO_PI: ::std::os::raw::c_uint = 4096;
 const IFF_VNET_HDR: ::std::os::raw::c_uint = 16384;
 
 const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
+ioctl_ior_nr!(TUNGETFEATURES, TUNTAP, 207, ::std::os::raw::c_uint);
+ioctl_ior_nr!(TUNGETIFF, TUNTAP, 210, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
 
+// Socket ioctls, as defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v6.1/source/include/uapi/linux/sockios.h
+const SIOCGIFFLAGS: u64 = 0x8913;
+const SIOCSIFFLAGS: u64 = 0x8914;
+const SIOCSIFADDR: u64 = 0x8916;
+const SIOCSIFNETMASK: u64 = 0x891c;
+const SIOCGIFINDEX: u64 = 0x8933;
+const SIOCBRADDIF: u64 = 0x89a2;
+
 /// Handle for a network tap interface.
 ///
 /// For now, this simply wraps the file descriptor for the tap device so methods
@@ -44,9 +59,154 @@ ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
 #[derive(Debug)]
 pub struct Tap {
     tap_file: File,
+    if_name: [u8; IFACE_NAME_MAX_LEN],
+    vnet_hdr: bool,
+    link_layer: LinkLayer,
+    // Network namespace the tap was created in, if not the VMM's.
+    netns: Option<File>,
+    // TUN_F_* flags last set with TUNSETOFFLOAD, the kernel has no getter.
+    offload_flags: AtomicU32,
 }
 
 impl Tap {
+    /// Whether the tap prepends a virtio net header (`IFF_VNET_HDR`) to the frames, as read back
+    /// with TUNGETIFF.
+    #[allow(dead_code)]
+    pub fn vnet_hdr(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    /// Whether the interface carries Ethernet frames or IP packets.
+    #[allow(dead_code)]
+    pub fn link_layer(&self) -> LinkLayer {
+        self.link_layer
+    }
+
+    /// Open or create the `if_name` tap in the network namespace at `netns_path`, e.g.
+    /// `/var/run/netns/<name>`. The calling thread switches to that namespace for the duration of
+    /// the call only; the tap then stays in it. Requires CAP_SYS_ADMIN.
+    ///
+    /// `attach_to_bridge` and `set_ip` then configure the tap from that namespace too.
+    #[allow(dead_code)]
+    pub fn open_named_in_netns(
+        if_name: &str,
+        link_layer: LinkLayer,
+        netns_path: &Path,
+    ) -> super::Result<Self> {
+        let netns = File::open(netns_path)
+            .map_err(|e| VirtioNetError::NetnsOpen(netns_path.to_path_buf(), e))?;
+        let tap = in_netns(&netns, || Tap::open_named(if_name, link_layer))?;
+
+        Ok(Tap {
+            netns: Some(netns),
+            ..tap
+        })
+    }
+
+    /// TUN_F_* offload flags enabled by the last `activate`, 0 before.
+    pub fn offload_flags(&self) -> u32 {
+        self.offload_flags.load(Ordering::SeqCst)
+    }
+
+    /// TUN_F_* offload flags the host tun driver supports, e.g. older kernels lack some.
+    ///
+    /// The kernel has no query for this, so each flag is tried with TUNSETOFFLOAD, then the flags
+    /// of the last `activate` are restored.
+    pub fn supported_offloads(&self) -> super::Result<u32> {
+        let mut supported = 0;
+        // Without TUN_F_CSUM, the kernel ignores the segmentation offloads instead of checking them.
+        for flag in [TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO] {
+            match self.set_offload(TUN_F_CSUM | flag) {
+                Ok(()) => supported |= flag,
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(e) => return Err(VirtioNetError::IoCtlError(e)),
+            }
+        }
+        self.set_offload(self.offload_flags())
+            .map_err(VirtioNetError::IoCtlError)?;
+
+        Ok(supported)
+    }
+
+    /// Add the tap interface to the `bridge` host bridge, so the guest gets upstream
+    /// connectivity. Requires CAP_NET_ADMIN.
+    #[allow(dead_code)]
+    pub fn attach_to_bridge(&self, bridge: &str) -> super::Result<()> {
+        let terminated_bridge_name = build_terminated_if_name(bridge)?;
+        let socket = self.control_socket()?;
+
+        let mut ifreq = IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .execute(&socket, SIOCGIFINDEX)?;
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let if_index = unsafe { *ifreq.ifr_ifru.ifru_ivalue.as_mut() };
+
+        IfReqBuilder::new()
+            .if_name(&terminated_bridge_name)
+            .if_index(if_index)
+            .execute(&socket, SIOCBRADDIF)
+            .map_err(|e| match e {
+                VirtioNetError::IoCtlError(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                    VirtioNetError::BridgeNotFound(bridge.to_string())
+                }
+                VirtioNetError::IoCtlError(e) => {
+                    VirtioNetError::BridgeAttach(bridge.to_string(), e)
+                }
+                e => e,
+            })?;
+
+        Ok(())
+    }
+
+    /// Assign `addr/prefix` to the host end of the tap and bring it up, for point-to-point
+    /// setups. This doesn't configure the guest interface. Requires CAP_NET_ADMIN.
+    #[allow(dead_code)]
+    pub fn set_ip(&self, addr: Ipv4Addr, prefix: u8) -> super::Result<()> {
+        let netmask = prefix_to_netmask(prefix).ok_or(VirtioNetError::InvalidPrefix(prefix))?;
+        let socket = self.control_socket()?;
+
+        IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .addr(addr)
+            .execute(&socket, SIOCSIFADDR)?;
+        // Setting the address resets the netmask to the class default, so set it afterwards.
+        IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .netmask(netmask)
+            .execute(&socket, SIOCSIFNETMASK)?;
+
+        let mut ifreq = IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .execute(&socket, SIOCGIFFLAGS)?;
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let flags = unsafe { *ifreq.ifr_ifru.ifru_flags.as_mut() };
+        IfReqBuilder::new()
+            .if_name(&self.if_name)
+            .flags(flags | libc::IFF_UP as i16)
+            .execute(&socket, SIOCSIFFLAGS)?;
+
+        Ok(())
+    }
+
+    // Returns a socket to issue ioctls on the tap with, created in the tap network namespace: the
+    // interface can't be found from the others.
+    fn control_socket(&self) -> super::Result<OwnedFd> {
+        match &self.netns {
+            Some(netns) => in_netns(netns, control_socket),
+            None => control_socket(),
+        }
+    }
+
+    fn set_offload(&self, flags: c_uint) -> IoResult<()> {
+        // Safe because we know that our file is a valid tap device and we verify the result.
+        let ret = unsafe { ioctl_with_val(self, TUNSETOFFLOAD(), flags as c_ulong) };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(())
+    }
+
     fn virtio_flags_to_tuntap_flags(virtio_flags: u64) -> c_uint {
         // Check if VIRTIO_NET_F_CSUM is set and set TUN_F_CSUM if so. Do the same for UFO, TSO6 and TSO4.
         let mut flags = 0;
@@ -69,12 +229,13 @@ impl Tap {
 
 impl Interface for Tap {
     fn activate(&self, virtio_flags: u64, virtio_header_size: usize) -> super::Result<()> {
-        let flags = Tap::virtio_flags_to_tuntap_flags(virtio_flags);
+        // Only request what the host supports, rather than failing on older kernels. The device
+        // learns what was enabled through `offload_flags`.
+        let flags = Tap::virtio_flags_to_tuntap_flags(virtio_flags) & self.supported_offloads()?;
 
-        let ret = unsafe { ioctl_with_val(self, TUNSETOFFLOAD(), flags as c_ulong) };
-        if ret < 0 {
-            return Err(std::io::Error::last_os_error()).map_err(VirtioNetError::IoCtlError);
-        }
+        self.set_offload(flags)
+            .map_err(VirtioNetError::IoCtlError)?;
+        self.offload_flags.store(flags, Ordering::SeqCst);
 
         // Safe because we know that our file is a valid tap device and we verify the result.
         let ret = unsafe { ioctl_with_ref(self, TUNSETVNETHDRSZ(), &virtio_header_size) };
@@ -85,7 +246,7 @@ impl Interface for Tap {
         Ok(())
     }
 
-    fn open_named(if_name: &str) -> super::Result<Self> {
+    fn open_named(if_name: &str, link_layer: LinkLayer) -> super::Result<Self> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -102,14 +263,50 @@ impl Interface for Tap {
         // We just checked that the fd is valid.
         let tuntap = unsafe { File::from_raw_fd(fd) };
 
+        // The frames we exchange with the tap are prefixed with a virtio net header, so the driver
+        // must support IFF_VNET_HDR. Check it upfront rather than failing later when setting the
+        // header size.
+        let mut features: c_uint = 0;
+        // Safe because we know that our file is a valid tap device and we verify the result.
+        let ret = unsafe { ioctl_with_mut_ref(&tuntap, TUNGETFEATURES(), &mut features) };
+        if ret < 0 {
+            return Err(IoError::last_os_error()).map_err(VirtioNetError::IoCtlError);
+        }
+        if features & IFF_VNET_HDR == 0 {
+            return Err(VirtioNetError::VnetHdrNotSupported);
+        }
+
+        let mode = match link_layer {
+            LinkLayer::Tap => IFF_TAP,
+            LinkLayer::Tun => IFF_TUN,
+        };
         IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((IFF_TAP | IFF_NO_PI | IFF_VNET_HDR) as i16)
-            .execute(&tuntap, TUNSETIFF())
-            .unwrap();
+            .flags((mode | IFF_NO_PI | IFF_VNET_HDR) as i16)
+            .execute(&tuntap, TUNSETIFF())?;
+
+        // Read the name and flags back to know what the kernel actually set up.
+        let mut ifreq = IfReqBuilder::new().execute(&tuntap, TUNGETIFF())?;
+        // Since we don't call as_mut on the same union fields more than once, this block is safe.
+        let (if_name, flags) = unsafe {
+            (
+                *ifreq.ifr_ifrn.ifrn_name.as_mut(),
+                *ifreq.ifr_ifru.ifru_flags.as_mut() as c_uint,
+            )
+        };
+        let vnet_hdr = flags & IFF_VNET_HDR != 0;
+        if !vnet_hdr {
+            return Err(VirtioNetError::VnetHdrNotSupported);
+        }
 
-        // Safe since only the name is accessed, and it's cloned out.
-        Ok(Tap { tap_file: tuntap })
+        Ok(Tap {
+            tap_file: tuntap,
+            if_name,
+            vnet_hdr,
+            link_layer,
+            netns: None,
+            offload_flags: AtomicU32::new(0),
+        })
     }
 }
 
@@ -130,6 +327,66 @@ fn build_terminated_if_name(if_name: &str) -> super::Result<[u8; IFACE_NAME_MAX_
     Ok(terminated_if_name)
 }
 
+// Runs `f` with the calling thread in the network namespace `netns` refers to, then moves the
+// thread back to its original namespace.
+fn in_netns<T>(netns: &File, f: impl FnOnce() -> super::Result<T>) -> super::Result<T> {
+    let original_netns = File::open("/proc/thread-self/ns/net").map_err(VirtioNetError::IoError)?;
+
+    set_netns(netns).map_err(VirtioNetError::NetnsEnter)?;
+    let result = f();
+    // Leaving the thread in the other namespace would affect everything it does next.
+    set_netns(&original_netns).map_err(VirtioNetError::NetnsRestore)?;
+
+    result
+}
+
+// Moves the calling thread to the network namespace `netns` refers to.
+fn set_netns(netns: &File) -> IoResult<()> {
+    // Safe because we pass a valid fd and check the result.
+    let ret = unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Returns a socket to issue network interface ioctls on.
+fn control_socket() -> super::Result<OwnedFd> {
+    // Safe because we check the result.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(IoError::last_os_error()).map_err(VirtioNetError::IoError);
+    }
+
+    // We just checked that the fd is valid.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// Returns the netmask matching a prefix length, if valid.
+fn prefix_to_netmask(prefix: u8) -> Option<Ipv4Addr> {
+    if prefix > 32 {
+        return None;
+    }
+
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    Some(Ipv4Addr::from(mask))
+}
+
+// Returns `addr` as a `struct sockaddr_in`, cast to a generic `struct sockaddr`.
+fn inet_sockaddr(addr: Ipv4Addr) -> sockaddr {
+    let mut sa_data = [0; 14];
+    // sockaddr_in starts with a 2-byte port, followed by the address in network byte order.
+    for (data, octet) in sa_data[2..6].iter_mut().zip(addr.octets()) {
+        *data = octet as c_char;
+    }
+
+    sockaddr {
+        sa_family: libc::AF_INET as u16,
+        sa_data,
+    }
+}
+
 pub struct IfReqBuilder(ifreq);
 
 impl IfReqBuilder {
@@ -154,6 +411,30 @@ impl IfReqBuilder {
         self
     }
 
+    pub(crate) fn if_index(mut self, if_index: i32) -> Self {
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let ifru_ivalue = unsafe { self.0.ifr_ifru.ifru_ivalue.as_mut() };
+        *ifru_ivalue = if_index;
+
+        self
+    }
+
+    pub(crate) fn addr(mut self, addr: Ipv4Addr) -> Self {
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let ifru_addr = unsafe { self.0.ifr_ifru.ifru_addr.as_mut() };
+        *ifru_addr = inet_sockaddr(addr);
+
+        self
+    }
+
+    pub(crate) fn netmask(mut self, netmask: Ipv4Addr) -> Self {
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let ifru_netmask = unsafe { self.0.ifr_ifru.ifru_netmask.as_mut() };
+        *ifru_netmask = inet_sockaddr(netmask);
+
+        self
+    }
+
     pub(crate) fn execute<F: AsRawFd>(mut self, socket: &F, ioctl: u64) -> super::Result<ifreq> {
         // ioctl is safe. Called with a valid socket fd, and we check the return.
         let ret = unsafe { ioctl_with_mut_ref(socket, ioctl, &mut self.0) };
@@ -186,3 +467,48 @@ impl AsRawFd for Tap {
         self.tap_file.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netmask_from_prefix() {
+        assert_eq!(prefix_to_netmask(0), Some(Ipv4Addr::new(0, 0, 0, 0)));
+        assert_eq!(prefix_to_netmask(24), Some(Ipv4Addr::new(255, 255, 255, 0)));
+        assert_eq!(
+            prefix_to_netmask(30),
+            Some(Ipv4Addr::new(255, 255, 255, 252))
+        );
+        assert_eq!(
+            prefix_to_netmask(32),
+            Some(Ipv4Addr::new(255, 255, 255, 255))
+        );
+        assert_eq!(prefix_to_netmask(33), None);
+    }
+
+    #[test]
+    fn open_in_missing_netns() {
+        let netns_path = Path::new("/var/run/netns/lumper-missing-netns");
+
+        assert!(matches!(
+            Tap::open_named_in_netns("lumper0", LinkLayer::Tap, netns_path),
+            Err(VirtioNetError::NetnsOpen(path, e))
+                if path == netns_path && e.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn sockaddr_in_layout() {
+        let sockaddr = inet_sockaddr(Ipv4Addr::new(192, 168, 1, 2));
+
+        assert_eq!(sockaddr.sa_family, libc::AF_INET as u16);
+        assert_eq!(
+            sockaddr.sa_data[..6]
+                .iter()
+                .map(|b| *b as u8)
+                .collect::<Vec<_>>(),
+            [0, 0, 192, 168, 1, 2]
+        );
+    }
+}
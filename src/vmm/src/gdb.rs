@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// Largest packet we accept, advertised to GDB through qSupported.
+const PACKET_SIZE: usize = 4096;
+// Size of the x86_64 general purpose registers and rip in a `g` reply.
+const GPR_BYTES: usize = 17 * 8;
+// Error reply for memory accesses outside of the guest memory (EFAULT).
+const EFAULT_REPLY: &str = "E0e";
+
+/// Minimal GDB remote serial protocol stub.
+///
+/// It only gives access to guest memory: registers are reported as unavailable, and there is no
+/// support for breakpoints or execution control. The `monitor regions` command lists the guest
+/// memory layout.
+pub(crate) struct GdbStub {
+    guest_memory: GuestMemoryMmap,
+    regions: String,
+}
+
+impl GdbStub {
+    pub fn new(guest_memory: GuestMemoryMmap, regions: String) -> Self {
+        GdbStub {
+            guest_memory,
+            regions,
+        }
+    }
+
+    /// Serve the GDB clients connecting to `listener`, one at a time.
+    pub fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| self.handle_client(stream)) {
+                eprintln!("GDB connection error: {}", e);
+            }
+        }
+    }
+
+    fn handle_client(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+            match self.handle_packet(&String::from_utf8_lossy(&packet)) {
+                Some(reply) => write_packet(&mut writer, &reply)?,
+                // The client detached or killed the session.
+                None => return write_packet(&mut writer, "OK"),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the reply to `packet`, or `None` when the client ends the session.
+    fn handle_packet(&self, packet: &str) -> Option<String> {
+        let reply = if packet == "?" {
+            // Pretend the guest was stopped by a SIGTRAP.
+            "S05".to_string()
+        } else if packet.starts_with("qSupported") {
+            format!("PacketSize={:x}", PACKET_SIZE)
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet.starts_with('H') {
+            "OK".to_string()
+        } else if packet == "g" {
+            // The vCPUs state isn't reachable from here, report the registers as unavailable.
+            "xx".repeat(GPR_BYTES)
+        } else if let Some(args) = packet.strip_prefix('m') {
+            self.read_memory(args)
+        } else if let Some(args) = packet.strip_prefix('M') {
+            self.write_memory(args)
+        } else if let Some(command) = packet.strip_prefix("qRcmd,") {
+            self.monitor(command)
+        } else if packet == "D" || packet == "k" {
+            return None;
+        } else {
+            // An empty reply tells GDB the packet isn't supported.
+            String::new()
+        };
+
+        Some(reply)
+    }
+
+    // `m addr,length`
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_addr_len(args) {
+            // Each byte takes two characters in the reply.
+            Some((addr, len)) if len <= PACKET_SIZE / 2 => (addr, len),
+            _ => return "E01".to_string(),
+        };
+
+        let mut data = vec![0u8; len];
+        match self.guest_memory.read_slice(&mut data, GuestAddress(addr)) {
+            Ok(()) => hex_encode(&data),
+            Err(_) => EFAULT_REPLY.to_string(),
+        }
+    }
+
+    // `M addr,length:XX...`
+    fn write_memory(&self, args: &str) -> String {
+        let (addr, data) = match args
+            .split_once(':')
+            .and_then(|(addr_len, data)| Some((parse_addr_len(addr_len)?, hex_decode(data)?)))
+        {
+            Some(((addr, len), data)) if len == data.len() => (addr, data),
+            _ => return "E01".to_string(),
+        };
+
+        match self.guest_memory.write_slice(&data, GuestAddress(addr)) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => EFAULT_REPLY.to_string(),
+        }
+    }
+
+    // `qRcmd,command`, sent by GDB's `monitor` command.
+    fn monitor(&self, command: &str) -> String {
+        let output = match hex_decode(command).as_deref() {
+            Some(b"regions") => self.regions.clone(),
+            _ => "Supported commands: regions\n".to_string(),
+        };
+
+        hex_encode(output.as_bytes())
+    }
+}
+
+fn checksum(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Reads the next packet, acknowledging it on `ack`. Returns `None` once the client disconnects.
+fn read_packet<R: Read, W: Write>(reader: &mut R, ack: &mut W) -> io::Result<Option<Vec<u8>>> {
+    let mut byte = [0u8; 1];
+
+    loop {
+        // Skip acknowledgments and interrupt requests until the start of a packet.
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut payload = Vec::new();
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            payload.push(byte[0]);
+        }
+
+        let mut received = [0u8; 2];
+        reader.read_exact(&mut received)?;
+        let received = std::str::from_utf8(&received)
+            .ok()
+            .and_then(|received| u8::from_str_radix(received, 16).ok());
+
+        if received == Some(checksum(&payload)) {
+            ack.write_all(b"+")?;
+            return Ok(Some(payload));
+        }
+
+        // Ask for a retransmission.
+        ack.write_all(b"-")?;
+    }
+}
+
+fn write_packet<W: Write>(writer: &mut W, payload: &str) -> io::Result<()> {
+    write!(writer, "${}#{:02x}", payload, checksum(payload.as_bytes()))?;
+    writer.flush()
+}
+
+fn parse_addr_len(args: &str) -> Option<(u64, usize)> {
+    let (addr, len) = args.split_once(',')?;
+
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::guest_memory_with;
+
+    fn stub() -> GdbStub {
+        GdbStub::new(
+            guest_memory_with(&[(0, 0x1000)]),
+            "regions list\n".to_string(),
+        )
+    }
+
+    #[test]
+    fn packet_framing() {
+        let mut input: &[u8] = b"+$m0,4#fd$m0,4#00$?#3f";
+        let mut acks = Vec::new();
+
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap(),
+            Some(b"m0,4".to_vec())
+        );
+        // The corrupted packet is skipped and a retransmission requested.
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap(),
+            Some(b"?".to_vec())
+        );
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), None);
+        assert_eq!(acks, b"+-+");
+
+        let mut output = Vec::new();
+        write_packet(&mut output, "OK").unwrap();
+        assert_eq!(output, b"$OK#9a");
+    }
+
+    #[test]
+    fn memory_access() {
+        let stub = stub();
+
+        assert_eq!(stub.handle_packet("M10,3:0a0b0c"), Some("OK".to_string()));
+        assert_eq!(stub.handle_packet("m f,5"), Some("E01".to_string()));
+        assert_eq!(stub.handle_packet("mf,5"), Some("000a0b0c00".to_string()));
+
+        // Out of the guest memory.
+        assert_eq!(stub.handle_packet("mffe,4"), Some(EFAULT_REPLY.to_string()));
+        assert_eq!(
+            stub.handle_packet("Mfff,2:0102"),
+            Some(EFAULT_REPLY.to_string())
+        );
+        // Length and data don't match.
+        assert_eq!(stub.handle_packet("M10,3:0a0b"), Some("E01".to_string()));
+    }
+
+    #[test]
+    fn session_packets() {
+        let stub = stub();
+
+        assert_eq!(stub.handle_packet("?"), Some("S05".to_string()));
+        assert_eq!(
+            stub.handle_packet("qRcmd,726567696f6e73"),
+            Some(hex_encode(b"regions list\n"))
+        );
+        assert_eq!(stub.handle_packet("vMustReplyEmpty"), Some(String::new()));
+        assert_eq!(stub.handle_packet("D"), None);
+    }
+}
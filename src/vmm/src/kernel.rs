@@ -2,16 +2,24 @@
 
 #![cfg(target_arch = "x86_64")]
 
+use std::cmp::{max, min};
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops;
 use std::path::PathBuf;
 use std::result;
+use std::time::Instant;
 
 use linux_loader::bootparam::boot_params;
 use linux_loader::cmdline::Cmdline;
 use linux_loader::configurator::{linux::LinuxBootConfigurator, BootConfigurator, BootParams};
-use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader, KernelLoaderResult};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader};
+use log::info;
+use vm_allocator::RangeInclusive;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
+use crate::memory::{MemoryMap, MemoryRegionKind, MMIO_GAP_END, MMIO_GAP_START};
+use crate::multiboot;
 use crate::{Error, Result};
 
 // x86_64 boot constants. See https://www.kernel.org/doc/Documentation/x86/boot.txt for the full
@@ -35,16 +43,50 @@ const EBDA_START: u64 = 0x0009_fc00;
 // TODO: this should be bindgen'ed and exported by linux-loader.
 // See https://github.com/rust-vmm/linux-loader/issues/51
 const E820_RAM: u32 = 1;
+// Reserved memory type.
+const E820_RESERVED: u32 = 2;
+// ACPI tables memory type, reclaimable by the guest once it parsed the tables.
+const E820_ACPI: u32 = 3;
+// Size of the E820 table in the zeropage, `E820_MAX_ENTRIES_ZEROPAGE` in the kernel.
+pub(crate) const E820_MAX_ENTRIES_ZEROPAGE: usize = 128;
 
 /// Address of the zeropage, where Linux kernel boot parameters are written.
 pub(crate) const ZEROPG_START: u64 = 0x7000;
 
-const HIMEM_START: u64 = 0x0010_0000; // 1 MB
+pub(crate) const HIMEM_START: u64 = 0x0010_0000; // 1 MB
+
+/// Start of the window for the ACPI tables, up to `HIMEM_START`. Guests without an RSDP address
+/// from the firmware look for it on a 16-byte boundary between 0xe0000 and 0xfffff.
+pub(crate) const ACPI_START: u64 = 0x000e_0000;
 
 /// Address where the kernel command line is written.
 const CMDLINE_START: u64 = 0x0002_0000;
+/// Address where the Multiboot2 boot information is written, up to the EBDA. It embeds the
+/// command line, so it takes its place.
+pub(crate) const MULTIBOOT_INFO_START: u64 = CMDLINE_START;
 // Default command line
-pub const DEFAULT_CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k panic=1 pci=off";
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k pci=off";
+// Alignment of the initial ramdisk, a page.
+const INITRD_ALIGNMENT: u64 = 0x1000;
+/// Default `panic=` value: reboot one second after a kernel panic.
+pub const DEFAULT_PANIC_TIMEOUT: i32 = 1;
+/// Log target of the boot sequence events, to filter them on.
+pub(crate) const BOOT_LOG_TARGET: &str = "boot";
+
+/// Log that the boot `phase` started at `start` succeeded, and how long it took.
+///
+/// The phases are, in order: `memory`, `kernel_load`, `acpi`, `bootparams`, `cmdline`,
+/// `initramfs` and `zeropage`. Multiboot2 kernels go through `memory`, `kernel_load`, `acpi`,
+/// `initramfs` and `bootparams`, the latter writing the boot information. Their names and the
+/// event format are stable, for log parsing.
+pub(crate) fn log_boot_phase(phase: &str, start: Instant) {
+    info!(
+        target: BOOT_LOG_TARGET,
+        "phase={} duration_us={}",
+        phase,
+        start.elapsed().as_micros()
+    );
+}
 
 fn add_e820_entry(
     params: &mut boot_params,
@@ -52,8 +94,8 @@ fn add_e820_entry(
     size: u64,
     mem_type: u32,
 ) -> result::Result<(), Error> {
-    if params.e820_entries >= params.e820_table.len() as u8 {
-        return Err(Error::E820Configuration);
+    if params.e820_entries as usize >= E820_MAX_ENTRIES_ZEROPAGE {
+        return Err(Error::E820TooManyEntries(params.e820_entries as usize + 1));
     }
 
     params.e820_table[params.e820_entries as usize].addr = addr;
@@ -64,17 +106,177 @@ fn add_e820_entry(
     Ok(())
 }
 
-/// Build boot parameters for ELF kernels following the Linux boot protocol.
+// Sorts `ranges` and merges the ones that overlap or are contiguous.
+fn coalesce_ranges(ranges: &[RangeInclusive]) -> Result<Vec<RangeInclusive>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start());
+
+    let mut merged: Vec<RangeInclusive> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start() <= last.end().saturating_add(1) => {
+                if range.end() > last.end() {
+                    *last =
+                        RangeInclusive::new(last.start(), range.end()).map_err(Error::Allocator)?;
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Fill the E820 table of `params` from the guest RAM and reserved ranges.
+///
+/// Contiguous or overlapping ranges of the same type are merged into a single entry, and the
+/// entries are written in ascending address order.
+///
+/// # Arguments
+///
+/// * `params` - boot parameters to fill.
+/// * `ram` - usable RAM ranges.
+/// * `reserved` - ranges the guest must not use as RAM.
+/// * `acpi` - ranges holding the ACPI tables.
+pub fn fill_e820(
+    params: &mut boot_params,
+    ram: &[RangeInclusive],
+    reserved: &[RangeInclusive],
+    acpi: &[RangeInclusive],
+) -> Result<()> {
+    let mut entries: Vec<(RangeInclusive, u32)> = Vec::new();
+    for (ranges, mem_type) in [
+        (ram, E820_RAM),
+        (reserved, E820_RESERVED),
+        (acpi, E820_ACPI),
+    ] {
+        entries.extend(
+            coalesce_ranges(ranges)?
+                .into_iter()
+                .map(|range| (range, mem_type)),
+        );
+    }
+    entries.sort_by_key(|(range, _)| range.start());
+
+    // Ranges of the same type were merged, so any overlap is between different types.
+    if entries
+        .windows(2)
+        .any(|pair| pair[0].0.overlaps(&pair[1].0))
+    {
+        return Err(Error::E820Configuration);
+    }
+
+    let total_entries = params.e820_entries as usize + entries.len();
+    if total_entries > E820_MAX_ENTRIES_ZEROPAGE {
+        return Err(Error::E820TooManyEntries(total_entries));
+    }
+
+    for (range, mem_type) in entries {
+        // Ranges are inclusive, `len()` accounts for the last byte.
+        add_e820_entry(params, range.start(), range.len(), mem_type)?;
+    }
+
+    Ok(())
+}
+
+/// Disagreement between the RAM advertised to the guest and the guest memory mappings.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Part of a RAM range is not backed by any guest memory region.
+    RamWithoutMapping(ops::RangeInclusive<u64>),
+    /// A guest memory region is not covered by any RAM range.
+    MappingWithoutRam(ops::RangeInclusive<u64>),
+}
+
+/// Check that every RAM range is backed by guest memory, and that every guest memory region is
+/// advertised as RAM, at least partially.
+///
+/// # Arguments
+///
+/// * `guest_memory` - guest memory
+/// * `ram` - RAM ranges reported to the guest.
+pub fn check_ram_mappings(guest_memory: &GuestMemoryMmap, ram: &[RangeInclusive]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    for range in ram {
+        let mut addr = range.start();
+        while addr <= range.end() {
+            let last = match guest_memory.find_region(GuestAddress(addr)) {
+                Some(region) => region.last_addr().raw_value(),
+                None => {
+                    // Nothing is mapped until the next region starts, or until the range ends.
+                    let hole_end = guest_memory
+                        .iter()
+                        .map(|region| region.start_addr().raw_value())
+                        .filter(|start| *start > addr)
+                        .min()
+                        .map_or(range.end(), |start| min(start - 1, range.end()));
+                    mismatches.push(Mismatch::RamWithoutMapping(addr..=hole_end));
+                    hole_end
+                }
+            };
+
+            match last.checked_add(1) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+    }
+
+    for region in guest_memory.iter() {
+        let start = region.start_addr().raw_value();
+        let end = region.last_addr().raw_value();
+        if !ram
+            .iter()
+            .any(|range| range.start() <= end && start <= range.end())
+        {
+            mismatches.push(Mismatch::MappingWithoutRam(start..=end));
+        }
+    }
+
+    mismatches
+}
+
+/// Guest RAM ranges: the low memory below the EBDA, and everything from `himem_start` to the end
+/// of guest memory.
 ///
 /// # Arguments
 ///
 /// * `guest_memory` - guest memory
 /// * `himem_start` - address where high memory starts.
-/// * `mmio_gap_start` - address where the MMIO gap starts.
-/// * `mmio_gap_end` - address where the MMIO gap ends.
-pub fn build_bootparams(
+pub fn ram_ranges(
     guest_memory: &GuestMemoryMmap,
     himem_start: GuestAddress,
+) -> Result<Vec<RangeInclusive>> {
+    let last_addr = guest_memory.last_addr();
+    if last_addr < himem_start {
+        return Err(Error::HimemStartPastMemEnd);
+    }
+
+    // Usable RAM below the EBDA.
+    let mut ram = vec![RangeInclusive::new(0, EBDA_START - 1).map_err(Error::Allocator)?];
+    // Usable RAM above the high memory start, region by region so that the holes between them,
+    // like the 32-bit MMIO gap, aren't reported as RAM.
+    for region in guest_memory.iter() {
+        let start = max(region.start_addr(), himem_start).raw_value();
+        let end = region.last_addr().raw_value();
+        if start < end {
+            ram.push(RangeInclusive::new(start, end).map_err(Error::Allocator)?);
+        }
+    }
+
+    Ok(ram)
+}
+
+/// Build boot parameters for ELF kernels following the Linux boot protocol.
+///
+/// # Arguments
+///
+/// * `guest_memory` - guest memory
+/// * `memory_map` - memory map to report in the E820 table.
+pub fn build_bootparams(
+    guest_memory: &GuestMemoryMmap,
+    memory_map: &MemoryMap,
 ) -> std::result::Result<boot_params, Error> {
     let mut params = boot_params::default();
 
@@ -83,23 +285,246 @@ pub fn build_bootparams(
     params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
 
-    // Add an entry for EBDA itself.
-    add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
+    let ram = memory_map.ranges(MemoryRegionKind::Ram);
 
-    // Add entries for the usable RAM regions.
-    let last_addr = guest_memory.last_addr();
-    add_e820_entry(
+    // Don't advertise RAM the guest would fault on.
+    let mismatches = check_ram_mappings(guest_memory, &ram);
+    if !mismatches.is_empty() {
+        return Err(Error::RamLayout(mismatches));
+    }
+
+    // The MMIO gap isn't memory, the guest finds its devices by other means.
+    fill_e820(
         &mut params,
-        himem_start.raw_value() as u64,
-        last_addr
-            .checked_offset_from(himem_start)
-            .ok_or(Error::HimemStartPastMemEnd)?,
-        E820_RAM,
+        &ram,
+        &memory_map.ranges(MemoryRegionKind::Reserved),
+        &memory_map.ranges(MemoryRegionKind::Acpi),
     )?;
 
     Ok(params)
 }
 
+/// Find where to load an initial ramdisk of `size` bytes: on the first page boundary after the
+/// end of the kernel (exclusive), provided the whole ramdisk fits in the same RAM range.
+pub fn initramfs_load_addr(
+    ram: &[RangeInclusive],
+    kernel_end: u64,
+    size: u64,
+) -> Result<GuestAddress> {
+    let addr = kernel_end
+        .checked_add(INITRD_ALIGNMENT - 1)
+        .map(|end| end & !(INITRD_ALIGNMENT - 1))
+        .ok_or(Error::InitramfsDoesNotFit(size))?;
+    let last = addr
+        .checked_add(max(size, 1) - 1)
+        .ok_or(Error::InitramfsDoesNotFit(size))?;
+
+    if ram
+        .iter()
+        .any(|range| range.start() <= addr && last <= range.end())
+    {
+        Ok(GuestAddress(addr))
+    } else {
+        Err(Error::InitramfsDoesNotFit(size))
+    }
+}
+
+/// Point the boot parameters to the initial ramdisk loaded at `addr`.
+///
+/// The header fields are 32 bits wide, so the whole ramdisk must sit below 4 GiB.
+pub fn set_ramdisk(params: &mut boot_params, addr: GuestAddress, size: u64) -> Result<()> {
+    if size == 0 {
+        return Err(Error::RamdiskEmpty);
+    }
+    match addr.raw_value().checked_add(size) {
+        Some(end) if end <= 1 << 32 => {}
+        _ => return Err(Error::RamdiskAddressTooHigh(addr, size)),
+    }
+
+    params.hdr.ramdisk_image = addr.raw_value() as u32;
+    params.hdr.ramdisk_size = size as u32;
+
+    Ok(())
+}
+
+/// Build the guest memory map: the RAM ranges from `ram_ranges`, the EBDA and legacy BIOS area
+/// up to `himem_start` as reserved, the ACPI tables window out of it if `acpi` is set, and the
+/// 32-bit MMIO gap.
+pub fn memory_map(
+    guest_memory: &GuestMemoryMmap,
+    himem_start: GuestAddress,
+    acpi: bool,
+) -> Result<MemoryMap> {
+    let mut map = MemoryMap::new();
+    for range in ram_ranges(guest_memory, himem_start)? {
+        map.add(range, MemoryRegionKind::Ram);
+    }
+
+    let reserved_end = if acpi {
+        map.add(
+            RangeInclusive::new(ACPI_START, himem_start.raw_value() - 1)
+                .map_err(Error::Allocator)?,
+            MemoryRegionKind::Acpi,
+        );
+        ACPI_START
+    } else {
+        himem_start.raw_value()
+    };
+    map.add(
+        RangeInclusive::new(EBDA_START, reserved_end - 1).map_err(Error::Allocator)?,
+        MemoryRegionKind::Reserved,
+    );
+    map.add(
+        RangeInclusive::new(MMIO_GAP_START, MMIO_GAP_END - 1).map_err(Error::Allocator)?,
+        MemoryRegionKind::Mmio,
+    );
+
+    Ok(map)
+}
+
+/// Write prebuilt ACPI tables at `ACPI_START`, in the window `memory_map` reports as ACPI memory.
+///
+/// The tables must start with the RSDP so the guest finds it when scanning the BIOS area, and
+/// their pointers must be absolute guest addresses.
+pub fn write_acpi_tables(guest_memory: &GuestMemoryMmap, tables: &[u8]) -> Result<()> {
+    if tables.is_empty() || tables.len() as u64 > HIMEM_START - ACPI_START {
+        return Err(Error::AcpiTablesSize(tables.len()));
+    }
+
+    guest_memory
+        .write_slice(tables, GuestAddress(ACPI_START))
+        .map_err(Error::AcpiTablesWrite)
+}
+
+/// Check that the kernel segments, from the lowest one to the end of the last one, fit in a
+/// single RAM range rather than overlapping reserved memory such as the EBDA.
+pub fn check_kernel_placement(
+    segments: &ops::RangeInclusive<u64>,
+    ram: &[RangeInclusive],
+) -> Result<()> {
+    if ram
+        .iter()
+        .any(|range| range.start() <= *segments.start() && *segments.end() <= range.end())
+    {
+        Ok(())
+    } else {
+        Err(Error::KernelOverlapsReserved(segments.clone()))
+    }
+}
+
+/// Guest physical range the `PT_LOAD` segments of an ELF64 kernel image span, `p_memsz`
+/// included, so it can be checked before anything is written to guest memory.
+///
+/// Returns `None` when the image can't be parsed, leaving the error to the ELF loader.
+pub fn elf_load_range<R: Read + Seek>(
+    kernel_image: &mut R,
+) -> Result<Option<ops::RangeInclusive<u64>>> {
+    kernel_image.rewind().map_err(Error::IO)?;
+    let range = read_elf_load_range(kernel_image);
+    kernel_image.rewind().map_err(Error::IO)?;
+
+    Ok(range)
+}
+
+fn read_elf_load_range<R: Read + Seek>(kernel_image: &mut R) -> Option<ops::RangeInclusive<u64>> {
+    const EHDR_SIZE: usize = 64;
+    const PHDR_SIZE: usize = 56;
+    const PT_LOAD: u32 = 1;
+
+    let u16_at =
+        |bytes: &[u8], offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |bytes: &[u8], offset: usize| {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    };
+    let u64_at = |bytes: &[u8], offset: usize| {
+        let mut field = [0u8; 8];
+        field.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(field)
+    };
+
+    let mut ehdr = [0u8; EHDR_SIZE];
+    kernel_image.read_exact(&mut ehdr).ok()?;
+    // ELF magic, 64-bit, little endian.
+    if ehdr[..6] != *b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let phoff = u64_at(&ehdr, 32);
+    let phentsize = u16_at(&ehdr, 54) as usize;
+    let phnum = u16_at(&ehdr, 56);
+    if phentsize < PHDR_SIZE {
+        return None;
+    }
+
+    let mut range: Option<(u64, u64)> = None;
+    let mut phdr = vec![0u8; phentsize];
+    for index in 0..u64::from(phnum) {
+        let offset = phoff.checked_add(index * phentsize as u64)?;
+        kernel_image.seek(SeekFrom::Start(offset)).ok()?;
+        kernel_image.read_exact(&mut phdr).ok()?;
+
+        let p_type = u32_at(&phdr, 0);
+        let p_paddr = u64_at(&phdr, 24);
+        let p_memsz = u64_at(&phdr, 40);
+        if p_type != PT_LOAD || p_memsz == 0 {
+            continue;
+        }
+
+        let last = p_paddr.checked_add(p_memsz - 1)?;
+        range = Some(match range {
+            Some((start, end)) => (min(start, p_paddr), max(end, last)),
+            None => (p_paddr, last),
+        });
+    }
+
+    range.map(|(start, end)| start..=end)
+}
+
+/// Boot protocol a kernel image expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootProtocol {
+    /// Linux 64-bit boot protocol, with the zeropage.
+    Linux,
+    /// Multiboot2, used by some unikernels and hobby kernels.
+    Multiboot,
+}
+
+/// Find out which boot protocol `kernel_image` expects, from the presence of a valid i386
+/// Multiboot2 header in its first 32 KiB.
+pub fn detect_boot_protocol<R: Read + Seek>(kernel_image: &mut R) -> Result<BootProtocol> {
+    let mut head = Vec::new();
+    kernel_image.rewind().map_err(Error::IO)?;
+    kernel_image
+        .by_ref()
+        .take(multiboot::HEADER_SEARCH_LEN)
+        .read_to_end(&mut head)
+        .map_err(Error::IO)?;
+    kernel_image.rewind().map_err(Error::IO)?;
+
+    if multiboot::find_header(&head).is_some() {
+        Ok(BootProtocol::Multiboot)
+    } else {
+        Ok(BootProtocol::Linux)
+    }
+}
+
+/// Where and how to enter a loaded kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootEntry {
+    /// Boot protocol of the kernel, which decides the initial vCPU state.
+    pub protocol: BootProtocol,
+    /// Entry point of the kernel.
+    pub entry: GuestAddress,
+    /// Boot information for the kernel: the zeropage, or the Multiboot2 information structure.
+    pub boot_info: GuestAddress,
+    /// End of the loaded kernel, exclusive.
+    pub kernel_end: u64,
+}
+
 /// Set guest kernel up.
 ///
 /// # Arguments
@@ -110,23 +535,92 @@ pub fn kernel_setup(
     guest_memory: &GuestMemoryMmap,
     kernel_path: PathBuf,
     initramfs_path: Option<String>,
+    acpi_tables: Option<&[u8]>,
+    cmdline: &Cmdline,
+) -> Result<BootEntry> {
+    let mut kernel_image = File::open(&kernel_path).map_err(|e| Error::OpenFile(kernel_path, e))?;
+
+    kernel_setup_from_reader(
+        guest_memory,
+        &mut kernel_image,
+        initramfs_path,
+        acpi_tables,
+        cmdline,
+    )
+}
+
+/// Set guest kernel up, reading the ELF kernel image from `kernel_image` rather than from a file.
+///
+/// This is useful when the kernel is already in memory or fetched from somewhere else than the
+/// local filesystem.
+pub fn kernel_setup_from_reader<R: Read + Seek>(
+    guest_memory: &GuestMemoryMmap,
+    kernel_image: &mut R,
+    initramfs_path: Option<String>,
+    acpi_tables: Option<&[u8]>,
     cmdline: &Cmdline,
-) -> Result<KernelLoaderResult> {
-    let mut kernel_image = File::open(kernel_path).map_err(Error::IO)?;
+) -> Result<BootEntry> {
+    match detect_boot_protocol(kernel_image)? {
+        BootProtocol::Linux => linux_setup(
+            guest_memory,
+            kernel_image,
+            initramfs_path,
+            acpi_tables,
+            cmdline,
+        ),
+        BootProtocol::Multiboot => multiboot_setup(
+            guest_memory,
+            kernel_image,
+            initramfs_path,
+            acpi_tables,
+            cmdline,
+        ),
+    }
+}
+
+fn linux_setup<R: Read + Seek>(
+    guest_memory: &GuestMemoryMmap,
+    kernel_image: &mut R,
+    initramfs_path: Option<String>,
+    acpi_tables: Option<&[u8]>,
+    cmdline: &Cmdline,
+) -> Result<BootEntry> {
+    let start = Instant::now();
     let zero_page_addr = GuestAddress(ZEROPG_START);
 
+    // Refuse kernels overlapping reserved memory before writing any of their segments.
+    let ram = ram_ranges(guest_memory, GuestAddress(HIMEM_START))?;
+    if let Some(segments) = elf_load_range(kernel_image)? {
+        check_kernel_placement(&segments, &ram)?;
+    }
+
     // Load the kernel into guest memory.
     let kernel_load = Elf::load(
         guest_memory,
         None,
-        &mut kernel_image,
+        kernel_image,
         Some(GuestAddress(HIMEM_START)),
     )
     .map_err(Error::KernelLoad)?;
+    log_boot_phase("kernel_load", start);
+
+    if let Some(tables) = acpi_tables {
+        let start = Instant::now();
+        write_acpi_tables(guest_memory, tables)?;
+        log_boot_phase("acpi", start);
+    }
 
     // Generate boot parameters.
-    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
+    let start = Instant::now();
+    let memory_map = memory_map(
+        guest_memory,
+        GuestAddress(HIMEM_START),
+        acpi_tables.is_some(),
+    )?;
+    let mut bootparams = build_bootparams(guest_memory, &memory_map)?;
+    log_boot_phase("bootparams", start);
 
+    let start = Instant::now();
     let cmdline_str = cmdline
         .as_cstring()
         .map_err(Error::Cmdline)?
@@ -146,30 +640,6 @@ pub fn kernel_setup(
         .insert_str(&cmdline_str)
         .map_err(Error::Cmdline)?;
 
-    // Add the initramfs to the boot parameters if one was provided.
-    if let Some(initramfs_path) = initramfs_path {
-        // Open the initramfs file
-        let mut initramfs_file = File::open(initramfs_path).map_err(Error::IO)?;
-        let initramfs_size = initramfs_file.metadata().unwrap().len() as usize;
-
-        // Find the address where the initramfs should be loaded.
-        // The initramfs is loaded right after the kernel.
-        let initramfs_address = kernel_load.kernel_end + 1;
-
-        // Load the initramfs into guest memory.
-        guest_memory
-            .read_from(
-                GuestAddress(initramfs_address),
-                &mut initramfs_file,
-                initramfs_size,
-            )
-            .map_err(|_| Error::InitramfsLoad)?;
-
-        // Set the initramfs address and size in the boot parameters.
-        bootparams.hdr.ramdisk_image = initramfs_address as u32;
-        bootparams.hdr.ramdisk_size = initramfs_size as u32;
-    }
-
     // Load the kernel command line into guest memory.
     load_cmdline(
         guest_memory,
@@ -178,13 +648,795 @@ pub fn kernel_setup(
         &shrinked_cmdline,
     )
     .map_err(Error::KernelLoad)?;
+    log_boot_phase("cmdline", start);
+
+    // Add the initramfs to the boot parameters if one was provided.
+    if let Some(initramfs_path) = initramfs_path {
+        let (initramfs_address, initramfs_size) =
+            load_initramfs(guest_memory, &ram, kernel_load.kernel_end, initramfs_path)?;
+
+        // Set the initramfs address and size in the boot parameters.
+        set_ramdisk(&mut bootparams, initramfs_address, initramfs_size)?;
+    }
 
     // Write the boot parameters in the zeropage.
+    let start = Instant::now();
     LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
         &BootParams::new::<boot_params>(&bootparams, zero_page_addr),
         guest_memory,
     )
     .map_err(Error::BootConfigure)?;
+    log_boot_phase("zeropage", start);
+
+    Ok(BootEntry {
+        protocol: BootProtocol::Linux,
+        entry: kernel_load.kernel_load,
+        boot_info: zero_page_addr,
+        kernel_end: kernel_load.kernel_end,
+    })
+}
+
+fn multiboot_setup<R: Read + Seek>(
+    guest_memory: &GuestMemoryMmap,
+    kernel_image: &mut R,
+    initramfs_path: Option<String>,
+    acpi_tables: Option<&[u8]>,
+    cmdline: &Cmdline,
+) -> Result<BootEntry> {
+    let start = Instant::now();
+    let kernel = multiboot::parse_kernel(kernel_image)?;
+
+    // Like Linux kernels, load the kernel in RAM above the boot structures in low memory, and
+    // refuse it before writing any of its segments otherwise.
+    let ram = ram_ranges(guest_memory, GuestAddress(HIMEM_START))?;
+    let high_ram: Vec<RangeInclusive> = ram
+        .iter()
+        .filter(|range| range.start() >= HIMEM_START)
+        .copied()
+        .collect();
+    let segments = kernel.range();
+    check_kernel_placement(&segments, &high_ram)?;
+
+    kernel.load(guest_memory, kernel_image)?;
+    log_boot_phase("kernel_load", start);
+
+    if let Some(tables) = acpi_tables {
+        let start = Instant::now();
+        write_acpi_tables(guest_memory, tables)?;
+        log_boot_phase("acpi", start);
+    }
+
+    // The initramfs is passed as a boot module, whose addresses are 32 bits wide.
+    let module = match initramfs_path {
+        Some(initramfs_path) => {
+            let (addr, size) =
+                load_initramfs(guest_memory, &ram, segments.end() + 1, initramfs_path)?;
+            let start = u32::try_from(addr.raw_value());
+            let end = addr
+                .raw_value()
+                .checked_add(size)
+                .and_then(|end| u32::try_from(end).ok());
+            match (start, end) {
+                (Ok(start), Some(end)) => Some(start..end),
+                _ => return Err(Error::RamdiskAddressTooHigh(addr, size)),
+            }
+        }
+        None => None,
+    };
+
+    let start = Instant::now();
+    let memory_map = memory_map(
+        guest_memory,
+        GuestAddress(HIMEM_START),
+        acpi_tables.is_some(),
+    )?;
+    let cmdline_str = cmdline
+        .as_cstring()
+        .map_err(Error::Cmdline)?
+        .into_string()
+        .map_err(Error::IntoStringError)?;
+    let info = multiboot::build_info(&cmdline_str, &memory_map, module);
+    if MULTIBOOT_INFO_START + info.len() as u64 > EBDA_START {
+        return Err(Error::MultibootInfoTooLarge(info.len()));
+    }
+    guest_memory
+        .write_slice(&info, GuestAddress(MULTIBOOT_INFO_START))
+        .map_err(Error::MultibootLoad)?;
+    log_boot_phase("bootparams", start);
+
+    Ok(BootEntry {
+        protocol: BootProtocol::Multiboot,
+        entry: kernel.entry,
+        boot_info: GuestAddress(MULTIBOOT_INFO_START),
+        kernel_end: segments.end() + 1,
+    })
+}
+
+// Load the initramfs at `initramfs_path` on the first page boundary after the kernel ending at
+// `kernel_end` (exclusive), and return its address and size.
+fn load_initramfs(
+    guest_memory: &GuestMemoryMmap,
+    ram: &[RangeInclusive],
+    kernel_end: u64,
+    initramfs_path: String,
+) -> Result<(GuestAddress, u64)> {
+    let start = Instant::now();
+    // Open the initramfs file
+    let mut initramfs_file = File::open(&initramfs_path)
+        .map_err(|e| Error::OpenFile(PathBuf::from(initramfs_path), e))?;
+    let initramfs_size = initramfs_file.metadata().map_err(Error::IO)?.len();
+
+    let initramfs_address = initramfs_load_addr(ram, kernel_end, initramfs_size)?;
+
+    // Load the initramfs into guest memory.
+    guest_memory
+        .read_from(
+            initramfs_address,
+            &mut initramfs_file,
+            initramfs_size as usize,
+        )
+        .map_err(Error::InitramfsLoad)?;
+    log_boot_phase("initramfs", start);
+
+    Ok((initramfs_address, initramfs_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::guest_memory_with;
+    use sha2::{Digest, Sha256};
+    use vm_memory::MemoryRegionAddress;
+
+    use std::fs::metadata;
+
+    const MEM_SIZE: usize = 128 << 20;
+
+    fn range(start: u64, end: u64) -> RangeInclusive {
+        RangeInclusive::new(start, end).unwrap()
+    }
+
+    // Builds a minimal ELF64 image with a single PT_LOAD segment holding `payload` at `load_addr`.
+    fn elf_image(load_addr: u64, payload: &[u8]) -> Vec<u8> {
+        elf_image_with(load_addr, load_addr, payload, payload.len() as u64)
+    }
+
+    // Like `elf_image`, with a separate entry point and a segment `memsz` bytes long in memory.
+    fn elf_image_with(entry: u64, load_addr: u64, payload: &[u8], memsz: u64) -> Vec<u8> {
+        const EHDR_SIZE: u16 = 64;
+        const PHDR_SIZE: u16 = 56;
+
+        let mut image = b"\x7fELF".to_vec();
+        // 64-bit, little endian, current version, padding.
+        image.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend_from_slice(&2u16.to_le_bytes()); // e_type: ET_EXEC
+        image.extend_from_slice(&62u16.to_le_bytes()); // e_machine: EM_X86_64
+        image.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        image.extend_from_slice(&entry.to_le_bytes()); // e_entry
+        image.extend_from_slice(&u64::from(EHDR_SIZE).to_le_bytes()); // e_phoff
+        image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        image.extend_from_slice(&EHDR_SIZE.to_le_bytes()); // e_ehsize
+        image.extend_from_slice(&PHDR_SIZE.to_le_bytes()); // e_phentsize
+        image.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        image.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+        let payload_offset = u64::from(EHDR_SIZE + PHDR_SIZE);
+        let payload_size = payload.len() as u64;
+        image.extend_from_slice(&1u32.to_le_bytes()); // p_type: PT_LOAD
+        image.extend_from_slice(&5u32.to_le_bytes()); // p_flags: R + X
+        image.extend_from_slice(&payload_offset.to_le_bytes()); // p_offset
+        image.extend_from_slice(&load_addr.to_le_bytes()); // p_vaddr
+        image.extend_from_slice(&load_addr.to_le_bytes()); // p_paddr
+        image.extend_from_slice(&payload_size.to_le_bytes()); // p_filesz
+        image.extend_from_slice(&memsz.to_le_bytes()); // p_memsz
+        image.extend_from_slice(&0x1000u64.to_le_bytes()); // p_align
+
+        image.extend_from_slice(payload);
+        image
+    }
+
+    // Copies the E820 table out of the packed `boot_params`.
+    fn e820_entries(params: &boot_params) -> Vec<(u64, u64, u32)> {
+        let table = params.e820_table;
+        table[..params.e820_entries as usize]
+            .iter()
+            .map(|entry| (entry.addr, entry.size, entry.type_))
+            .collect()
+    }
+
+    #[test]
+    fn fill_e820_uses_inclusive_sizes() {
+        let mut params = boot_params::default();
+        fill_e820(
+            &mut params,
+            &[range(0x1000, 0x1fff)],
+            &[range(0x2000, 0x2fff)],
+            &[range(0x3000, 0x37ff)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            e820_entries(&params),
+            vec![
+                (0x1000, 0x1000, E820_RAM),
+                (0x2000, 0x1000, E820_RESERVED),
+                (0x3000, 0x800, E820_ACPI)
+            ]
+        );
+    }
+
+    #[test]
+    fn fill_e820_coalesces_ranges() {
+        let mut params = boot_params::default();
+        let ram = [
+            range(0x3000, 0x3fff),
+            range(0x1000, 0x1fff),
+            range(0x2000, 0x2fff),
+            range(0x3800, 0x4fff),
+            range(0x8000, 0x8fff),
+        ];
+        fill_e820(&mut params, &ram, &[range(0x6000, 0x6fff)], &[]).unwrap();
+
+        assert_eq!(
+            e820_entries(&params),
+            vec![
+                (0x1000, 0x4000, E820_RAM),
+                (0x6000, 0x1000, E820_RESERVED),
+                (0x8000, 0x1000, E820_RAM),
+            ]
+        );
+    }
+
+    #[test]
+    fn fill_e820_rejects_reserved_overlapping_ram() {
+        let mut params = boot_params::default();
+        assert!(matches!(
+            fill_e820(
+                &mut params,
+                &[range(0x1000, 0x2fff)],
+                &[range(0x2000, 0x3fff)],
+                &[]
+            ),
+            Err(Error::E820Configuration)
+        ));
+
+        let mut params = boot_params::default();
+        assert!(matches!(
+            fill_e820(
+                &mut params,
+                &[range(0x1000, 0x2fff)],
+                &[],
+                &[range(0x2fff, 0x3fff)]
+            ),
+            Err(Error::E820Configuration)
+        ));
+    }
+
+    #[test]
+    fn fill_e820_checks_capacity() {
+        assert_eq!(
+            boot_params::default().e820_table.len(),
+            E820_MAX_ENTRIES_ZEROPAGE
+        );
+
+        let max_entries = E820_MAX_ENTRIES_ZEROPAGE as u64;
+        // Leave a hole between each range so they can't be merged.
+        let ram: Vec<RangeInclusive> = (0..=max_entries)
+            .map(|i| range(i * 0x2000, i * 0x2000 + 0xfff))
+            .collect();
+
+        let mut params = boot_params::default();
+        assert!(matches!(
+            fill_e820(&mut params, &ram[..ram.len() - 1], &[], &[]),
+            Ok(())
+        ));
+
+        let mut params = boot_params::default();
+        assert!(matches!(
+            fill_e820(&mut params, &ram, &[], &[]),
+            Err(Error::E820TooManyEntries(count)) if count == E820_MAX_ENTRIES_ZEROPAGE + 1
+        ));
+        // Nothing was written.
+        assert_eq!(params.e820_entries, 0);
+    }
+
+    #[test]
+    fn check_ram_mappings_matching_layout() {
+        let guest_memory = guest_memory_with(&[(0, 0x1_0000), (0x2_0000, 0x1_0000)]);
+
+        assert!(check_ram_mappings(
+            &guest_memory,
+            &[range(0, 0x9fff), range(0x2_0000, 0x2_ffff)]
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn check_ram_mappings_ram_without_mapping() {
+        let guest_memory = guest_memory_with(&[(0, 0x1_0000), (0x2_0000, 0x1_0000)]);
+
+        assert_eq!(
+            check_ram_mappings(&guest_memory, &[range(0x8000, 0x3_7fff)]),
+            vec![
+                Mismatch::RamWithoutMapping(0x1_0000..=0x1_ffff),
+                Mismatch::RamWithoutMapping(0x3_0000..=0x3_7fff),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_ram_mappings_mapping_without_ram() {
+        let guest_memory = guest_memory_with(&[(0, 0x1_0000), (0x2_0000, 0x1_0000)]);
+
+        assert_eq!(
+            check_ram_mappings(&guest_memory, &[range(0, 0xffff)]),
+            vec![Mismatch::MappingWithoutRam(0x2_0000..=0x2_ffff)]
+        );
+    }
 
-    Ok(kernel_load)
+    #[test]
+    fn build_bootparams_registers_low_ram() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let memory_map = memory_map(&guest_memory, GuestAddress(HIMEM_START), false).unwrap();
+        let params = build_bootparams(&guest_memory, &memory_map).unwrap();
+
+        // The real-mode trampoline lives below the EBDA, so this range must be usable RAM.
+        assert_eq!(
+            e820_entries(&params),
+            vec![
+                (0, EBDA_START, E820_RAM),
+                (EBDA_START, HIMEM_START - EBDA_START, E820_RESERVED),
+                (HIMEM_START, MEM_SIZE as u64 - HIMEM_START, E820_RAM),
+            ]
+        );
+        assert_eq!(
+            memory_map.entries().last(),
+            Some((MMIO_GAP_START..=MMIO_GAP_END - 1, MemoryRegionKind::Mmio))
+        );
+    }
+
+    #[test]
+    fn initramfs_is_page_aligned_in_ram() {
+        let ram = [range(0, EBDA_START - 1), range(HIMEM_START, 0x3f_ffff)];
+
+        assert_eq!(
+            initramfs_load_addr(&ram, 0x20_0001, 0x1000).unwrap(),
+            GuestAddress(0x20_1000)
+        );
+        // Already aligned.
+        assert_eq!(
+            initramfs_load_addr(&ram, 0x20_0000, 0x1000).unwrap(),
+            GuestAddress(0x20_0000)
+        );
+        // Fits exactly up to the end of RAM.
+        assert_eq!(
+            initramfs_load_addr(&ram, 0x3f_f000, 0x1000).unwrap(),
+            GuestAddress(0x3f_f000)
+        );
+        assert!(matches!(
+            initramfs_load_addr(&ram, 0x3f_f001, 0x1000),
+            Err(Error::InitramfsDoesNotFit(0x1000))
+        ));
+        assert!(matches!(
+            initramfs_load_addr(&ram, u64::MAX - 1, 0x1000),
+            Err(Error::InitramfsDoesNotFit(0x1000))
+        ));
+    }
+
+    #[test]
+    fn ramdisk_below_4g() {
+        let mut params = boot_params::default();
+        set_ramdisk(&mut params, GuestAddress(0xffff_f000), 0x1000).unwrap();
+        let (ramdisk_image, ramdisk_size) = (params.hdr.ramdisk_image, params.hdr.ramdisk_size);
+        assert_eq!((ramdisk_image, ramdisk_size), (0xffff_f000, 0x1000));
+
+        assert!(matches!(
+            set_ramdisk(&mut params, GuestAddress(0xffff_f000), 0x1001),
+            Err(Error::RamdiskAddressTooHigh(
+                GuestAddress(0xffff_f000),
+                0x1001
+            ))
+        ));
+        assert!(matches!(
+            set_ramdisk(&mut params, GuestAddress(1 << 32), 1),
+            Err(Error::RamdiskAddressTooHigh(..))
+        ));
+        assert!(matches!(
+            set_ramdisk(&mut params, GuestAddress(u64::MAX), 2),
+            Err(Error::RamdiskAddressTooHigh(..))
+        ));
+        assert!(matches!(
+            set_ramdisk(&mut params, GuestAddress(HIMEM_START), 0),
+            Err(Error::RamdiskEmpty)
+        ));
+    }
+
+    #[test]
+    fn acpi_tables_are_reported() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let tables = b"RSD PTR tables";
+
+        write_acpi_tables(&guest_memory, tables).unwrap();
+        let memory_map = memory_map(&guest_memory, GuestAddress(HIMEM_START), true).unwrap();
+        let params = build_bootparams(&guest_memory, &memory_map).unwrap();
+        assert_eq!(
+            e820_entries(&params),
+            vec![
+                (0, EBDA_START, E820_RAM),
+                (EBDA_START, ACPI_START - EBDA_START, E820_RESERVED),
+                (ACPI_START, HIMEM_START - ACPI_START, E820_ACPI),
+                (HIMEM_START, MEM_SIZE as u64 - HIMEM_START, E820_RAM),
+            ]
+        );
+
+        let mut written = [0u8; 14];
+        guest_memory
+            .read_slice(&mut written, GuestAddress(ACPI_START))
+            .unwrap();
+        assert_eq!(&written, tables);
+    }
+
+    #[test]
+    fn acpi_tables_must_fit_window() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let too_large = vec![0u8; (HIMEM_START - ACPI_START) as usize + 1];
+
+        assert!(matches!(
+            write_acpi_tables(&guest_memory, &[]),
+            Err(Error::AcpiTablesSize(0))
+        ));
+        assert!(matches!(
+            write_acpi_tables(&guest_memory, &too_large),
+            Err(Error::AcpiTablesSize(len)) if len == too_large.len()
+        ));
+    }
+
+    #[test]
+    fn kernel_placement_within_ram() {
+        let ram = [
+            range(0, EBDA_START - 1),
+            range(HIMEM_START, MEM_SIZE as u64 - 1),
+        ];
+
+        assert!(check_kernel_placement(&(HIMEM_START..=HIMEM_START + 0xfff), &ram).is_ok());
+        // Over the EBDA.
+        assert!(matches!(
+            check_kernel_placement(&(0x9_f000..=0x9_ffff), &ram),
+            Err(Error::KernelOverlapsReserved(range)) if range == (0x9_f000..=0x9_ffff)
+        ));
+        // Past the end of the guest memory, e.g. because of a large BSS.
+        assert!(matches!(
+            check_kernel_placement(&(HIMEM_START..=MEM_SIZE as u64), &ram),
+            Err(Error::KernelOverlapsReserved(_))
+        ));
+    }
+
+    #[test]
+    fn elf_load_range_spans_segments() {
+        let image = elf_image_with(HIMEM_START, 0x20_0000, &[0xf4; 0x100], 0x1000);
+        assert_eq!(
+            elf_load_range(&mut std::io::Cursor::new(image)).unwrap(),
+            Some(0x20_0000..=0x20_0fff)
+        );
+        // Not an ELF image, left to the loader.
+        assert_eq!(
+            elf_load_range(&mut std::io::Cursor::new(vec![0u8; 0x100])).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn kernel_with_low_segment_is_rejected() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let cmdline = Cmdline::new(4096).unwrap();
+        // The entry point is in high memory, but the segment lands on the EBDA.
+        let mut kernel_image = std::io::Cursor::new(elf_image_with(
+            HIMEM_START,
+            0x9_f000,
+            &[0xf4; 0x1000],
+            0x1000,
+        ));
+
+        assert!(matches!(
+            kernel_setup_from_reader(&guest_memory, &mut kernel_image, None, None, &cmdline),
+            Err(Error::KernelOverlapsReserved(range)) if range == (0x9_f000..=0x9_ffff)
+        ));
+        // Nothing was written over the EBDA.
+        let ebda: u8 = guest_memory.read_obj(GuestAddress(EBDA_START)).unwrap();
+        assert_eq!(ebda, 0);
+    }
+
+    #[test]
+    fn kernel_past_end_of_ram_is_rejected() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let cmdline = Cmdline::new(4096).unwrap();
+        // A BSS running past the end of the guest memory.
+        let mut kernel_image = std::io::Cursor::new(elf_image_with(
+            HIMEM_START,
+            HIMEM_START,
+            &[0xf4; 0x1000],
+            MEM_SIZE as u64,
+        ));
+
+        assert!(matches!(
+            kernel_setup_from_reader(&guest_memory, &mut kernel_image, None, None, &cmdline),
+            Err(Error::KernelOverlapsReserved(range))
+                if range == (HIMEM_START..=HIMEM_START + MEM_SIZE as u64 - 1)
+        ));
+    }
+
+    // Appends a Multiboot2 header for `architecture`, with an end tag, at an 8-byte aligned
+    // offset.
+    fn with_multiboot2_header(
+        mut image: Vec<u8>,
+        architecture: u32,
+        checksum_delta: u32,
+    ) -> Vec<u8> {
+        image.resize((image.len() + 7) & !7, 0);
+        let header_length = 24u32;
+        let checksum = 0u32
+            .wrapping_sub(multiboot::HEADER_MAGIC)
+            .wrapping_sub(architecture)
+            .wrapping_sub(header_length)
+            .wrapping_add(checksum_delta);
+
+        for field in [
+            multiboot::HEADER_MAGIC,
+            architecture,
+            header_length,
+            checksum,
+            0,
+            8,
+        ] {
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn detect_multiboot2_kernels() {
+        let elf = elf_image(HIMEM_START, &[0xf4; 0x100]);
+        let mut linux = std::io::Cursor::new(elf.clone());
+        assert_eq!(
+            detect_boot_protocol(&mut linux).unwrap(),
+            BootProtocol::Linux
+        );
+        // The reader is left at the start of the image for the loader.
+        assert_eq!(linux.position(), 0);
+
+        let mut multiboot = std::io::Cursor::new(with_multiboot2_header(elf.clone(), 0, 0));
+        assert_eq!(
+            detect_boot_protocol(&mut multiboot).unwrap(),
+            BootProtocol::Multiboot
+        );
+
+        // Headers with a bad checksum, or for another architecture, are skipped.
+        let mut corrupted = std::io::Cursor::new(with_multiboot2_header(elf.clone(), 0, 1));
+        assert_eq!(
+            detect_boot_protocol(&mut corrupted).unwrap(),
+            BootProtocol::Linux
+        );
+        let mut mips = std::io::Cursor::new(with_multiboot2_header(elf, 4, 0));
+        assert_eq!(
+            detect_boot_protocol(&mut mips).unwrap(),
+            BootProtocol::Linux
+        );
+    }
+
+    #[test]
+    fn kernel_setup_for_multiboot2_kernels() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let mut cmdline = Cmdline::new(4096).unwrap();
+        cmdline.insert_str(DEFAULT_CMDLINE).unwrap();
+        let payload = [0xf4; 0x100];
+        let image = with_multiboot2_header(elf_image(HIMEM_START, &payload), 0, 0);
+
+        let boot_entry = kernel_setup_from_reader(
+            &guest_memory,
+            &mut std::io::Cursor::new(image),
+            Some(test_image(TEST_INITRAMFS).to_str().unwrap().to_string()),
+            None,
+            &cmdline,
+        )
+        .unwrap();
+        assert_eq!(
+            boot_entry,
+            BootEntry {
+                protocol: BootProtocol::Multiboot,
+                entry: GuestAddress(HIMEM_START),
+                boot_info: GuestAddress(MULTIBOOT_INFO_START),
+                kernel_end: HIMEM_START + payload.len() as u64,
+            }
+        );
+
+        let mut loaded = [0u8; 0x100];
+        guest_memory
+            .read_slice(&mut loaded, GuestAddress(HIMEM_START))
+            .unwrap();
+        assert_eq!(loaded, payload);
+
+        // The boot information starts with its size, then the command line tag.
+        let total_size: u32 = guest_memory
+            .read_obj(GuestAddress(MULTIBOOT_INFO_START))
+            .unwrap();
+        assert!(u64::from(total_size) < EBDA_START - MULTIBOOT_INFO_START);
+        let mut written_cmdline = vec![0u8; DEFAULT_CMDLINE.len() + 1];
+        guest_memory
+            .read_slice(
+                &mut written_cmdline,
+                GuestAddress(MULTIBOOT_INFO_START + 16),
+            )
+            .unwrap();
+        assert_eq!(
+            written_cmdline,
+            format!("{}\0", DEFAULT_CMDLINE).into_bytes()
+        );
+
+        // No zeropage for Multiboot2 kernels.
+        let params: boot_params = guest_memory.read_obj(GuestAddress(ZEROPG_START)).unwrap();
+        let boot_flag = params.hdr.boot_flag;
+        assert_eq!(boot_flag, 0);
+    }
+
+    #[test]
+    fn multiboot2_kernel_below_himem_is_rejected() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let cmdline = Cmdline::new(4096).unwrap();
+        let image = with_multiboot2_header(elf_image(0x1_0000, &[0xf4; 0x100]), 0, 0);
+
+        assert!(matches!(
+            kernel_setup_from_reader(
+                &guest_memory,
+                &mut std::io::Cursor::new(image),
+                None,
+                None,
+                &cmdline
+            ),
+            Err(Error::KernelOverlapsReserved(range)) if range == (0x1_0000..=0x1_00ff)
+        ));
+    }
+
+    #[test]
+    fn kernel_setup_from_in_memory_image() {
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let mut cmdline = Cmdline::new(4096).unwrap();
+        cmdline.insert_str(DEFAULT_CMDLINE).unwrap();
+        let payload = [0xf4; 0x100];
+        let mut kernel_image = std::io::Cursor::new(elf_image(HIMEM_START, &payload));
+
+        let boot_entry =
+            kernel_setup_from_reader(&guest_memory, &mut kernel_image, None, None, &cmdline)
+                .unwrap();
+        assert_eq!(boot_entry.entry, GuestAddress(HIMEM_START));
+
+        let mut loaded = [0u8; 0x100];
+        guest_memory
+            .read_slice(&mut loaded, GuestAddress(HIMEM_START))
+            .unwrap();
+        assert_eq!(loaded, payload);
+
+        let params: boot_params = guest_memory.read_obj(GuestAddress(ZEROPG_START)).unwrap();
+        let boot_flag = params.hdr.boot_flag;
+        assert_eq!(boot_flag, KERNEL_BOOT_FLAG_MAGIC);
+    }
+
+    #[test]
+    fn kernel_setup_with_split_memory() {
+        const HIGH_RAM_SIZE: usize = 0x10_0000;
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE), (MMIO_GAP_END, HIGH_RAM_SIZE)]);
+        let mut cmdline = Cmdline::new(4096).unwrap();
+        cmdline.insert_str(DEFAULT_CMDLINE).unwrap();
+        let payload = [0xf4; 0x100];
+        let mut kernel_image = std::io::Cursor::new(elf_image(HIMEM_START, &payload));
+
+        let boot_entry =
+            kernel_setup_from_reader(&guest_memory, &mut kernel_image, None, None, &cmdline)
+                .unwrap();
+
+        // The kernel, the command line and the zeropage all land in the low region.
+        let low_region = guest_memory.find_region(GuestAddress(0)).unwrap();
+        assert_eq!(boot_entry.entry, GuestAddress(HIMEM_START));
+        assert!(boot_entry.kernel_end <= low_region.last_addr().raw_value());
+        let mut loaded = [0u8; 0x100];
+        low_region
+            .read_slice(&mut loaded, MemoryRegionAddress(HIMEM_START))
+            .unwrap();
+        assert_eq!(loaded, payload);
+
+        let mut written_cmdline = vec![0u8; DEFAULT_CMDLINE.len()];
+        low_region
+            .read_slice(&mut written_cmdline, MemoryRegionAddress(CMDLINE_START))
+            .unwrap();
+        assert_eq!(written_cmdline, DEFAULT_CMDLINE.as_bytes());
+
+        // Both regions are reported as RAM, not the gap in between.
+        let params: boot_params = guest_memory.read_obj(GuestAddress(ZEROPG_START)).unwrap();
+        assert_eq!(
+            e820_entries(&params),
+            vec![
+                (0, EBDA_START, E820_RAM),
+                (EBDA_START, HIMEM_START - EBDA_START, E820_RESERVED),
+                (HIMEM_START, MEM_SIZE as u64 - HIMEM_START, E820_RAM),
+                (MMIO_GAP_END, HIGH_RAM_SIZE as u64, E820_RAM),
+            ]
+        );
+    }
+
+    // Minimal kernel and initramfs bundled for `kernel_setup_writes_zeropage`, with their SHA-256.
+    const TEST_KERNEL: (&str, &str) = (
+        "resources/test/vmlinux",
+        "af341db790c063406c04200f7d466d059db09f63b4c70392a1174e1b63778425",
+    );
+    const TEST_INITRAMFS: (&str, &str) = (
+        "resources/test/initramfs.cpio",
+        "219e7889cf1023cbc6b1655e30aee93abf7b50c983cf2c2834be31f576be7747",
+    );
+
+    // Returns the path of a bundled test image, after checking it wasn't altered.
+    fn test_image((path, sha256): (&str, &str)) -> PathBuf {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path);
+        let digest = Sha256::digest(std::fs::read(&path).unwrap());
+        assert_eq!(
+            format!("{:x}", digest),
+            sha256,
+            "{} was modified",
+            path.display()
+        );
+
+        path
+    }
+
+    // Runs the whole boot setup against the bundled kernel and initramfs and checks the zeropage
+    // left in guest memory. No vCPU is created, so this needs neither root nor /dev/kvm.
+    #[test]
+    fn kernel_setup_writes_zeropage() {
+        let kernel_path = test_image(TEST_KERNEL);
+        let initramfs_path = test_image(TEST_INITRAMFS);
+
+        let guest_memory = guest_memory_with(&[(0, MEM_SIZE)]);
+        let mut cmdline = Cmdline::new(4096).unwrap();
+        cmdline.insert_str(DEFAULT_CMDLINE).unwrap();
+
+        let boot_entry = kernel_setup(
+            &guest_memory,
+            kernel_path,
+            Some(initramfs_path.to_str().unwrap().to_string()),
+            None,
+            &cmdline,
+        )
+        .unwrap();
+        assert_eq!(boot_entry.protocol, BootProtocol::Linux);
+        assert!(boot_entry.entry.raw_value() >= HIMEM_START);
+
+        let params: boot_params = guest_memory.read_obj(GuestAddress(ZEROPG_START)).unwrap();
+
+        // `boot_params` is packed, copy the fields out before comparing them.
+        let boot_flag = params.hdr.boot_flag;
+        let header = params.hdr.header;
+        let cmd_line_ptr = params.hdr.cmd_line_ptr;
+        let cmdline_size = params.hdr.cmdline_size;
+        let e820_entries = params.e820_entries;
+        assert_eq!(boot_flag, KERNEL_BOOT_FLAG_MAGIC);
+        assert_eq!(header, KERNEL_HDR_MAGIC);
+        assert_eq!(cmd_line_ptr, CMDLINE_START as u32);
+        assert_eq!(cmdline_size as usize, DEFAULT_CMDLINE.len() + 1);
+        assert_eq!(e820_entries, 3);
+
+        let mut written_cmdline = vec![0u8; DEFAULT_CMDLINE.len()];
+        guest_memory
+            .read_slice(&mut written_cmdline, GuestAddress(CMDLINE_START))
+            .unwrap();
+        assert_eq!(written_cmdline, DEFAULT_CMDLINE.as_bytes());
+
+        let ramdisk_image = params.hdr.ramdisk_image;
+        let ramdisk_size = params.hdr.ramdisk_size;
+        // `kernel_end` is exclusive, the ramdisk may start right there.
+        assert!(u64::from(ramdisk_image) >= boot_entry.kernel_end);
+        assert_eq!(u64::from(ramdisk_image) % INITRD_ALIGNMENT, 0);
+        assert_eq!(
+            u64::from(ramdisk_size),
+            metadata(initramfs_path).unwrap().len()
+        );
+    }
 }
@@ -8,32 +8,63 @@ extern crate linux_loader;
 extern crate vm_memory;
 extern crate vm_superio;
 
-use std::fs::File;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io::stdout;
+use std::net::{TcpListener, ToSocketAddrs};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use std::{io, path::PathBuf};
 
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
 use kvm_ioctls::{Kvm, VmFd};
-use linux_loader::loader::{self, KernelLoaderResult};
+use linux_loader::loader;
+use log::info;
 use vm_device::device_manager::IoManager;
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap,
+};
+use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 mod cpu;
 use cpu::{cpuid, mptable, Vcpu};
 mod devices;
-use devices::serial::LumperSerial;
+use devices::i8042::LumperI8042;
+use devices::serial::{EventFdTrigger, LumperSerial};
 use vm_allocator::IdAllocator;
 
 mod epoll_context;
 use epoll_context::{EpollContext, EPOLL_EVENTS_LEN};
+mod gdb;
+use gdb::GdbStub;
 mod kernel;
+use kernel::{BootEntry, BootProtocol};
+mod memory;
+pub use memory::{
+    current_usage, set_memory_budget, HugePageSize, MemoryMap, MemoryRegionInfo, MemoryRegionKind,
+};
+use memory::MemoryReservation;
+mod multiboot;
+mod seccomp;
+pub use seccomp::required_syscalls;
+#[cfg(test)]
+mod test_support;
 
 const CMDLINE_MAX_SIZE: usize = 4096;
 
+/// Why the guest stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExit {
+    /// The guest reset the CPU, e.g. to reboot.
+    Reboot,
+    /// The guest halted for good, e.g. after powering off.
+    Shutdown,
+}
+
 #[derive(Debug)]
 
 /// VMM errors.
@@ -44,20 +75,68 @@ pub enum Error {
     Cmdline(linux_loader::cmdline::Error),
     /// Failed to load kernel.
     KernelLoad(loader::Error),
+    /// Failed to open the kernel or initramfs file.
+    OpenFile(PathBuf, io::Error),
     /// Failed to load initrd.
-    InitramfsLoad,
+    InitramfsLoad(GuestMemoryError),
+    /// The initial ramdisk of this size doesn't fit in RAM after the kernel.
+    InitramfsDoesNotFit(u64),
+    /// The initial ramdisk is empty.
+    RamdiskEmpty,
+    /// The initial ramdisk at this address and of this size doesn't fit below 4 GiB.
+    RamdiskAddressTooHigh(GuestAddress, u64),
     /// Invalid E820 configuration.
     E820Configuration,
+    /// The ACPI tables are empty or don't fit below the high memory start.
+    AcpiTablesSize(usize),
+    /// Mapping this many bytes would exceed the process memory budget, which has this many left.
+    MemoryBudgetExceeded(u64, u64),
+    /// Failed to map memory added to the guest.
+    AddMemory(vm_memory::mmap::MmapRegionError),
+    /// The guest memory file is smaller than the guest memory (file size, required size).
+    MemoryFileTooSmall(u64, u64),
+    /// A guest memory file can't be combined with anonymous huge pages.
+    MemoryFileWithHugePages,
+    /// Failed to write the ACPI tables to guest memory.
+    AcpiTablesWrite(GuestMemoryError),
+    /// The guest CID is one of the reserved ones, below 3.
+    ReservedGuestCid(u32),
+    /// The E820 map needs more entries than the zeropage can hold.
+    E820TooManyEntries(usize),
     /// Highmem start address is past the guest memory end.
     HimemStartPastMemEnd,
+    /// The RAM advertised to the guest doesn't match the guest memory mappings.
+    RamLayout(Vec<kernel::Mismatch>),
+    /// The Multiboot2 header tags are malformed or inconsistent.
+    InvalidMultibootHeader,
+    /// The Multiboot2 kernel requires a header tag of this type, which isn't supported.
+    UnsupportedMultibootTag(u16),
+    /// The Multiboot2 kernel requires boot information of this type, which isn't provided.
+    UnsupportedMultibootInfo(u32),
+    /// The Multiboot2 kernel has neither an address tag nor valid ELF program headers.
+    InvalidMultibootKernel,
+    /// The Multiboot2 kernel entry point is above 4 GiB.
+    MultibootEntryTooHigh(u64),
+    /// The Multiboot2 boot information of this size doesn't fit below the EBDA.
+    MultibootInfoTooLarge(usize),
+    /// Failed to write the Multiboot2 kernel or boot information to guest memory.
+    MultibootLoad(GuestMemoryError),
+    /// The kernel image was loaded outside of the guest RAM.
+    KernelOverlapsReserved(std::ops::RangeInclusive<u64>),
     /// I/O error.
     IO(io::Error),
+    /// Failed to create the eventfds signalling guest resets and shutdowns.
+    ExitEventCreation(io::Error),
     /// Error issuing an ioctl to KVM.
     KvmIoctl(kvm_ioctls::Error),
     /// vCPU errors.
     Vcpu(cpu::Error),
     /// Memory error.
     Memory(vm_memory::Error),
+    /// Failed to map guest memory with huge pages. Are enough huge pages reserved on the host?
+    HugePages(vm_memory::mmap::MmapRegionError),
+    /// Guest memory regions are not aligned to the huge page size.
+    HugePagesAlignment(HugePageSize),
     /// Serial creation error
     SerialCreation(io::Error),
     /// IRQ registration error
@@ -76,6 +155,169 @@ pub enum Error {
     Allocator(vm_allocator::Error),
     /// IntoString error
     IntoStringError(std::ffi::IntoStringError),
+    /// GDB stub error
+    Gdb(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BootConfigure(e) => write!(f, "failed to write boot parameters: {}", e),
+            Error::Cmdline(e) => write!(f, "invalid kernel command line: {}", e),
+            Error::KernelLoad(e) => write!(f, "failed to load kernel: {}", e),
+            Error::OpenFile(path, e) => write!(f, "failed to open {}: {}", path.display(), e),
+            Error::InitramfsLoad(e) => write!(f, "failed to load initramfs: {}", e),
+            Error::InitramfsDoesNotFit(size) => write!(
+                f,
+                "initramfs of {} bytes doesn't fit in the guest RAM after the kernel",
+                size
+            ),
+            Error::RamdiskEmpty => write!(f, "the initial ramdisk is empty"),
+            Error::RamdiskAddressTooHigh(addr, size) => write!(
+                f,
+                "initial ramdisk of {:#x} bytes at {:#x} doesn't fit below 4 GiB",
+                size,
+                addr.raw_value()
+            ),
+            Error::E820Configuration => write!(f, "invalid E820 configuration"),
+            Error::AcpiTablesSize(len) => write!(
+                f,
+                "invalid ACPI tables size {:#x}, must be between 1 and {:#x} bytes",
+                len,
+                kernel::HIMEM_START - kernel::ACPI_START
+            ),
+            Error::MemoryBudgetExceeded(requested, left) => write!(
+                f,
+                "mapping {} bytes of guest memory would exceed the memory budget, {} bytes left",
+                requested, left
+            ),
+            Error::AddMemory(e) => write!(f, "failed to map additional guest memory: {}", e),
+            Error::MemoryFileTooSmall(size, required) => write!(
+                f,
+                "guest memory file is {} bytes, at least {} are required",
+                size, required
+            ),
+            Error::MemoryFileWithHugePages => write!(
+                f,
+                "huge pages can't be used with a guest memory file, use a file on hugetlbfs instead"
+            ),
+            Error::AcpiTablesWrite(e) => write!(f, "failed to write the ACPI tables: {}", e),
+            Error::ReservedGuestCid(cid) => write!(
+                f,
+                "guest CID {} is reserved, it must be at least {}",
+                cid, MIN_GUEST_CID
+            ),
+            Error::E820TooManyEntries(count) => write!(
+                f,
+                "the E820 map needs {} entries, the zeropage only holds {}: reduce the number of \
+                 memory regions or make them contiguous so they can be merged",
+                count,
+                kernel::E820_MAX_ENTRIES_ZEROPAGE
+            ),
+            Error::HimemStartPastMemEnd => {
+                write!(f, "high memory start is past the guest memory end")
+            }
+            Error::InvalidMultibootHeader => write!(f, "invalid Multiboot2 header tags"),
+            Error::UnsupportedMultibootTag(tag) => write!(
+                f,
+                "the kernel requires the unsupported Multiboot2 header tag {}",
+                tag
+            ),
+            Error::UnsupportedMultibootInfo(tag) => write!(
+                f,
+                "the kernel requires the Multiboot2 boot information {}, which isn't provided",
+                tag
+            ),
+            Error::InvalidMultibootKernel => write!(
+                f,
+                "the Multiboot2 kernel has neither an address tag nor valid ELF program headers"
+            ),
+            Error::MultibootEntryTooHigh(entry) => write!(
+                f,
+                "the Multiboot2 kernel entry point {:#x} is above 4 GiB",
+                entry
+            ),
+            Error::MultibootInfoTooLarge(size) => write!(
+                f,
+                "the Multiboot2 boot information takes {} bytes, too many to fit below the EBDA",
+                size
+            ),
+            Error::MultibootLoad(e) => write!(
+                f,
+                "failed to write the Multiboot2 kernel to guest memory: {}",
+                e
+            ),
+            Error::KernelOverlapsReserved(range) => write!(
+                f,
+                "kernel segments at {:#x}-{:#x}, outside of the guest RAM",
+                range.start(),
+                range.end()
+            ),
+            Error::RamLayout(mismatches) => write!(
+                f,
+                "guest RAM doesn't match the memory mappings: {:?}",
+                mismatches
+            ),
+            Error::IO(e) => write!(f, "I/O error: {}", e),
+            Error::KvmIoctl(e) => write!(f, "KVM ioctl failed: {}", e),
+            Error::Vcpu(e) => write!(f, "vCPU error: {:?}", e),
+            Error::Memory(e) => write!(f, "guest memory error: {}", e),
+            Error::HugePages(e) => write!(
+                f,
+                "failed to map guest memory with huge pages (are enough huge pages reserved?): {}",
+                e
+            ),
+            Error::HugePagesAlignment(size) => write!(
+                f,
+                "guest memory is not aligned to the {:?} huge page size",
+                size
+            ),
+            Error::SerialCreation(e) => write!(f, "failed to create the serial device: {}", e),
+            Error::ExitEventCreation(e) => {
+                write!(f, "failed to create the VM exit eventfds: {}", e)
+            }
+            Error::IrqRegister(e) => write!(f, "failed to register IRQ: {}", e),
+            Error::EpollError(e) => write!(f, "epoll error: {}", e),
+            Error::StdinRead(e) => write!(f, "failed to read stdin: {}", e),
+            Error::StdinWrite(e) => {
+                write!(f, "failed to write stdin to the serial device: {:?}", e)
+            }
+            Error::TerminalConfigure(e) => write!(f, "failed to configure the terminal: {}", e),
+            Error::ConsoleError(e) => write!(f, "failed to open the console file: {}", e),
+            Error::Allocator(e) => write!(f, "allocator error: {:?}", e),
+            Error::IntoStringError(e) => write!(f, "invalid kernel command line string: {}", e),
+            Error::Gdb(e) => write!(f, "failed to start the GDB stub: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::BootConfigure(e) => Some(e),
+            Error::Cmdline(e) => Some(e),
+            Error::KernelLoad(e) => Some(e),
+            Error::OpenFile(_, e) => Some(e),
+            Error::InitramfsLoad(e) => Some(e),
+            Error::AcpiTablesWrite(e) => Some(e),
+            Error::MultibootLoad(e) => Some(e),
+            Error::IO(e) => Some(e),
+            Error::KvmIoctl(e) => Some(e),
+            Error::Memory(e) => Some(e),
+            Error::HugePages(e) => Some(e),
+            Error::AddMemory(e) => Some(e),
+            Error::SerialCreation(e) => Some(e),
+            Error::ExitEventCreation(e) => Some(e),
+            Error::IrqRegister(e) => Some(e),
+            Error::EpollError(e) => Some(e),
+            Error::StdinRead(e) => Some(e),
+            Error::TerminalConfigure(e) => Some(e),
+            Error::ConsoleError(e) => Some(e),
+            Error::IntoStringError(e) => Some(e),
+            Error::Gdb(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
@@ -88,6 +330,8 @@ const IOAPIC_MAX_IRQ : u32 = 23;
 const SERIAL1_IRQ: u32 = 4;
 /// minimal IRQ for the virtio devices
 const X86_IRQ_BASE: u32 = SERIAL1_IRQ + 1;
+/// Lowest guest CID: 0 and 1 are reserved, 2 is the host.
+const MIN_GUEST_CID: u32 = 3;
 
 pub struct VMM {
     vm_fd: VmFd,
@@ -96,10 +340,19 @@ pub struct VMM {
     vcpus: Vec<Vcpu>,
 
     serial: Arc<Mutex<LumperSerial>>,
+    i8042: Arc<Mutex<LumperI8042>>,
+    reset_evt: EventFdTrigger,
+    shutdown_evt: EventFd,
     virtio_manager: Arc<Mutex<IoManager>>,
     epoll: EpollContext,
 
     cmdline: linux_loader::cmdline::Cmdline,
+    panic_timeout: i32,
+    acpi_tables: Option<Vec<u8>>,
+    memory_file: Option<PathBuf>,
+    guest_cid: Option<u32>,
+    // Keeps the guest memory accounted against the process budget while the VMM lives.
+    memory_reservations: Vec<MemoryReservation>,
     irq_allocator: IdAllocator,
 }
 
@@ -116,6 +369,19 @@ impl VMM {
         let epoll = EpollContext::new().map_err(Error::EpollError)?;
         epoll.add_stdin().map_err(Error::EpollError)?;
 
+        // Signalled when the guest resets the CPU, through the i8042 controller or a triple
+        // fault, and when it halts for good.
+        let reset_evt =
+            EventFdTrigger::new(libc::EFD_NONBLOCK).map_err(Error::ExitEventCreation)?;
+        let shutdown_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::ExitEventCreation)?;
+        epoll
+            .add_event(reset_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+        epoll
+            .add_event(shutdown_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+        let i8042 = LumperI8042::new(reset_evt.try_clone().map_err(Error::ExitEventCreation)?);
+
         let vmm = VMM {
             vm_fd,
             kvm,
@@ -124,45 +390,171 @@ impl VMM {
             serial: Arc::new(Mutex::new(
                 LumperSerial::new(Box::new(stdout())).map_err(Error::SerialCreation)?,
             )),
+            i8042: Arc::new(Mutex::new(i8042)),
+            reset_evt,
+            shutdown_evt,
             virtio_manager: Arc::new(Mutex::new(IoManager::new())),
             epoll,
             irq_allocator: IdAllocator::new(X86_IRQ_BASE, IOAPIC_MAX_IRQ).map_err(Error::Allocator)?,
             cmdline: linux_loader::cmdline::Cmdline::new(CMDLINE_MAX_SIZE)
                 .map_err(Error::Cmdline)?,
+            panic_timeout: kernel::DEFAULT_PANIC_TIMEOUT,
+            acpi_tables: None,
+            memory_file: None,
+            guest_cid: None,
+            memory_reservations: Vec::new(),
         };
 
         Ok(vmm)
     }
 
-    pub fn configure_memory(&mut self, mem_size_mb: u32) -> Result<()> {
+    pub fn configure_memory(
+        &mut self,
+        mem_size_mb: u32,
+        hugepages: Option<HugePageSize>,
+    ) -> Result<()> {
+        let start = Instant::now();
         // Convert memory size from MBytes to bytes.
         let mem_size = ((mem_size_mb as u64) << 20) as usize;
 
-        // Create one single memory region, from zero to mem_size.
-        let mem_regions = vec![(GuestAddress(0), mem_size)];
+        // Create the memory regions from zero, leaving out the 32-bit MMIO gap.
+        let mem_regions = memory::ram_regions(mem_size as u64);
 
         // Allocate the guest memory from the memory region.
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?;
+        let memory_file = match &self.memory_file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .map_err(|e| Error::OpenFile(path.clone(), e))?,
+            ),
+            None => None,
+        };
+        let (guest_memory, reservation) =
+            memory::build_guest_memory(&mem_regions, hugepages, memory_file.as_ref())?;
 
         // For each memory region in guest_memory:
         // 1. Create a KVM memory region mapping the memory region guest physical address to the host virtual address.
         // 2. Register the KVM memory region with KVM. EPTs are created then.
         for (index, region) in guest_memory.iter().enumerate() {
-            let kvm_memory_region = kvm_userspace_memory_region {
-                slot: index as u32,
-                guest_phys_addr: region.start_addr().raw_value(),
-                memory_size: region.len() as u64,
-                // It's safe to unwrap because the guest address is valid.
-                userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap() as u64,
-                flags: 0,
-            };
-
-            // Register the KVM memory region with KVM.
-            unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }
-                .map_err(Error::KvmIoctl)?;
+            self.register_memory_region(index as u32, region)?;
         }
 
         self.guest_memory = guest_memory;
+        // The previous guest memory, if any, is gone.
+        self.memory_reservations = vec![reservation];
+        kernel::log_boot_phase("memory", start);
+
+        Ok(())
+    }
+
+    fn register_memory_region(&self, slot: u32, region: &GuestRegionMmap) -> Result<()> {
+        let kvm_memory_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len() as u64,
+            userspace_addr: region.as_ptr() as u64,
+            flags: 0,
+        };
+
+        // Register the KVM memory region with KVM.
+        // Safe because the region stays mapped as long as the guest memory holds it.
+        unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }.map_err(Error::KvmIoctl)
+    }
+
+    /// Map `size_mib` MiB of anonymous memory right above the current guest memory, or above the
+    /// 32-bit MMIO gap if it would overlap it, register it with KVM, and return its guest physical
+    /// range.
+    ///
+    /// The guest isn't notified: it only uses the new memory if it is added before the kernel is
+    /// set up, or once it learns about it by other means. Must be called after `configure_memory`
+    /// or `configure`, which replace the whole guest memory.
+    pub fn add_memory(&mut self, size_mib: u32) -> Result<std::ops::RangeInclusive<u64>> {
+        let size = (size_mib as usize) << 20;
+        let mut start = self
+            .guest_memory
+            .iter()
+            .map(|region| region.last_addr().raw_value() + 1)
+            .max()
+            .unwrap_or(0);
+        // Don't map RAM over the 32-bit MMIO gap, continue above it.
+        if start < memory::MMIO_GAP_END && start + size as u64 > memory::MMIO_GAP_START {
+            start = memory::MMIO_GAP_END;
+        }
+
+        let (region, reservation) = memory::build_region(GuestAddress(start), size)?;
+        let region = Arc::new(region);
+        self.register_memory_region(self.guest_memory.num_regions() as u32, &region)?;
+        self.guest_memory = self
+            .guest_memory
+            .insert_region(region)
+            .map_err(Error::Memory)?;
+        self.memory_reservations.push(reservation);
+
+        Ok(start..=start + size as u64 - 1)
+    }
+
+    /// Guest physical memory map, as reported to the guest in the E820 table. Compare it with the
+    /// guest's `/proc/iomem` to debug memory layout issues.
+    pub fn memory_map(&self) -> Result<MemoryMap> {
+        kernel::memory_map(
+            &self.guest_memory,
+            GuestAddress(kernel::HIMEM_START),
+            self.acpi_tables.is_some(),
+        )
+    }
+
+    /// Describe the guest memory layout: where each region lives in the guest and in the VMM
+    /// address space, and what the memory map tells the guest it is used for.
+    pub fn memory_regions(&self) -> Result<Vec<MemoryRegionInfo>> {
+        let memory_map = self.memory_map()?;
+
+        let mut regions = Vec::new();
+        for region in self.guest_memory.iter() {
+            let start = region.start_addr().raw_value();
+            let end = region.last_addr().raw_value();
+
+            for (range, kind) in memory::split_by_kind(start, end, &memory_map) {
+                let guest_base = GuestAddress(*range.start());
+                regions.push(MemoryRegionInfo {
+                    guest_base,
+                    size: range.end() - range.start() + 1,
+                    // Safe to unwrap because the address belongs to a guest memory region.
+                    host_ptr: self.guest_memory.get_host_address(guest_base).unwrap(),
+                    kind,
+                });
+            }
+        }
+
+        Ok(regions)
+    }
+
+    /// Start a GDB remote stub listening on `listen_addr`.
+    ///
+    /// The stub runs in its own thread and gives access to the guest memory only. The memory
+    /// layout, as returned by `memory_regions`, is available through the `monitor regions` GDB
+    /// command. Must be called once the guest memory is configured.
+    pub fn attach_gdb<A: ToSocketAddrs>(&self, listen_addr: A) -> Result<()> {
+        let regions = self
+            .memory_regions()?
+            .iter()
+            .map(|region| {
+                format!(
+                    "{:#018x}-{:#018x} {}\n",
+                    region.guest_base.raw_value(),
+                    region.guest_base.raw_value() + region.size - 1,
+                    region.kind.label()
+                )
+            })
+            .collect();
+
+        let listener = TcpListener::bind(listen_addr).map_err(Error::Gdb)?;
+        let stub = GdbStub::new(self.guest_memory.clone(), regions);
+        thread::Builder::new()
+            .name("gdb".to_string())
+            .spawn(move || stub.serve(listener))
+            .map_err(Error::Gdb)?;
 
         Ok(())
     }
@@ -170,9 +562,61 @@ impl VMM {
     pub fn load_default_cmdline(&mut self) -> Result<()> {
         self.cmdline
             .insert_str(kernel::DEFAULT_CMDLINE)
+            .map_err(Error::Cmdline)?;
+        self.cmdline
+            .insert_str(format!("panic={}", self.panic_timeout))
             .map_err(Error::Cmdline)
     }
 
+    /// Keep the guest halted after a kernel panic instead of rebooting it (`panic=0`).
+    ///
+    /// The panic message and backtrace stay on the serial console, which makes this the mode to
+    /// use with `--console` when investigating a crash. Must be called before `configure`.
+    pub fn on_panic_halt(&mut self) {
+        self.panic_timeout = 0;
+    }
+
+    /// Reboot the guest `secs` seconds after a kernel panic, or immediately if `secs` is 0.
+    ///
+    /// The default is to reboot after 1 second. Must be called before `configure`.
+    pub fn on_panic_reboot(&mut self, secs: u32) {
+        // `panic=0` means waiting forever, a negative value reboots immediately.
+        self.panic_timeout = match secs {
+            0 => -1,
+            secs => i32::try_from(secs).unwrap_or(i32::MAX),
+        };
+    }
+
+    /// Map the guest memory from `path` with `MAP_SHARED` instead of anonymous memory, e.g. to
+    /// share it with another process or keep it after the VM exits. The file must already be at
+    /// least as large as the guest memory. Must be called before `configure`.
+    pub fn set_memory_file(&mut self, path: PathBuf) {
+        self.memory_file = Some(path);
+    }
+
+    /// Provide prebuilt ACPI tables, starting with the RSDP. They are written at 0xe0000 and
+    /// reported to the guest as ACPI memory in the E820 map. Must be called before `configure`.
+    pub fn set_acpi_tables(&mut self, tables: Vec<u8>) {
+        self.acpi_tables = Some(tables);
+    }
+
+    /// Set the context identifier (CID) identifying the guest, e.g. on vsock. CIDs 0 to 2 are
+    /// reserved and refused with `ReservedGuestCid`.
+    pub fn set_guest_cid(&mut self, cid: u32) -> Result<()> {
+        if cid < MIN_GUEST_CID {
+            return Err(Error::ReservedGuestCid(cid));
+        }
+        self.guest_cid = Some(cid);
+
+        Ok(())
+    }
+
+    /// Context identifier of the guest, for the devices that need to identify it. None until
+    /// `set_guest_cid` is called.
+    pub fn guest_cid(&self) -> Option<u32> {
+        self.guest_cid
+    }
+
     pub fn configure_io(&mut self) -> Result<()> {
         // First, create the irqchip.
         // On `x86_64`, this _must_ be created _before_ the vCPUs.
@@ -211,11 +655,7 @@ impl VMM {
         Ok(())
     }
 
-    pub fn configure_vcpus(
-        &mut self,
-        num_vcpus: u8,
-        kernel_load: KernelLoaderResult,
-    ) -> Result<()> {
+    pub fn configure_vcpus(&mut self, num_vcpus: u8, boot_entry: BootEntry) -> Result<()> {
         mptable::setup_mptable(&self.guest_memory, num_vcpus)
             .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
 
@@ -229,6 +669,7 @@ impl VMM {
                 &self.vm_fd,
                 index.into(),
                 Arc::clone(&self.serial),
+                Arc::clone(&self.i8042),
                 Arc::clone(&self.virtio_manager),
             )
             .map_err(Error::Vcpu)?;
@@ -247,10 +688,19 @@ impl VMM {
             vcpu.configure_msrs().map_err(Error::Vcpu)?;
 
             // Configure regs, sregs and fpu.
-            vcpu.configure_regs(kernel_load.kernel_load)
-                .map_err(Error::Vcpu)?;
-            vcpu.configure_sregs(&self.guest_memory)
-                .map_err(Error::Vcpu)?;
+            match boot_entry.protocol {
+                BootProtocol::Linux => {
+                    vcpu.configure_regs(boot_entry.entry).map_err(Error::Vcpu)?;
+                    vcpu.configure_sregs(&self.guest_memory)
+                        .map_err(Error::Vcpu)?;
+                }
+                BootProtocol::Multiboot => {
+                    vcpu.configure_multiboot_regs(boot_entry.entry, boot_entry.boot_info)
+                        .map_err(Error::Vcpu)?;
+                    vcpu.configure_multiboot_sregs(&self.guest_memory)
+                        .map_err(Error::Vcpu)?;
+                }
+            }
             vcpu.configure_fpu().map_err(Error::Vcpu)?;
 
             // Configure LAPICs.
@@ -262,12 +712,29 @@ impl VMM {
         Ok(())
     }
 
-    // Run all virtual CPUs.
-    pub fn run(&mut self) -> Result<()> {
+    /// Run the guest until it reboots or shuts down.
+    ///
+    /// The other vCPUs aren't stopped when this returns: the VMM is meant to be dropped, or the
+    /// process to exit, to tear the VM down.
+    pub fn run(&mut self) -> Result<VmExit> {
         for mut vcpu in self.vcpus.drain(..) {
-            println!("Starting vCPU {:?}", vcpu.index);
+            info!("Starting vCPU {:?}", vcpu.index);
+            let reset_evt = self.reset_evt.try_clone().map_err(Error::ExitEventCreation)?;
+            let shutdown_evt = self
+                .shutdown_evt
+                .try_clone()
+                .map_err(Error::ExitEventCreation)?;
             let _ = thread::Builder::new().spawn(move || loop {
-                vcpu.run();
+                if let Some(exit) = vcpu.run() {
+                    let exit_evt = match exit {
+                        VmExit::Reboot => &*reset_evt,
+                        VmExit::Shutdown => &shutdown_evt,
+                    };
+                    if let Err(e) = exit_evt.write(1) {
+                        eprintln!("Failed to signal the guest {:?}: {}", exit, e);
+                    }
+                    break;
+                }
             });
         }
 
@@ -278,6 +745,8 @@ impl VMM {
             .map_err(Error::TerminalConfigure)?;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
         let epoll_fd = self.epoll.as_raw_fd();
+        let reset_fd = self.reset_evt.as_raw_fd();
+        let shutdown_fd = self.shutdown_evt.as_raw_fd();
 
         // Let's start the STDIN polling thread.
         loop {
@@ -299,6 +768,21 @@ impl VMM {
                         .enqueue_raw_bytes(&out[..count])
                         .map_err(Error::StdinWrite)?;
                 }
+
+                let exit = if event_data == reset_fd {
+                    VmExit::Reboot
+                } else if event_data == shutdown_fd {
+                    VmExit::Shutdown
+                } else {
+                    continue;
+                };
+
+                println!("Guest {:?}. Bye!", exit);
+                stdin_lock
+                    .set_canon_mode()
+                    .map_err(Error::TerminalConfigure)?;
+
+                return Ok(exit);
             }
         }
     }
@@ -310,18 +794,20 @@ impl VMM {
         kernel_path: &str,
         console: Option<String>,
         initramfs_path: Option<String>,
+        hugepages: Option<HugePageSize>,
     ) -> Result<()> {
         self.configure_console(console)?;
-        self.configure_memory(mem_size_mb)?;
+        self.configure_memory(mem_size_mb, hugepages)?;
         self.load_default_cmdline()?;
-        let kernel_load = kernel::kernel_setup(
+        let boot_entry = kernel::kernel_setup(
             &self.guest_memory,
             PathBuf::from(kernel_path),
             initramfs_path,
+            self.acpi_tables.as_deref(),
             &self.cmdline,
         )?;
         self.configure_io()?;
-        self.configure_vcpus(num_vcpus, kernel_load)?;
+        self.configure_vcpus(num_vcpus, boot_entry)?;
 
         Ok(())
     }
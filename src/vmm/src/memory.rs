@@ -0,0 +1,501 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::cmp::{max, min};
+use std::fmt;
+use std::fs::File;
+use std::ops;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use vm_allocator::RangeInclusive;
+use vm_memory::{Address, FileOffset, GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+use crate::{Error, Result};
+
+// Process-wide cap on the guest memory mapped by all the VMMs, and what they currently use.
+static MEMORY_BUDGET: AtomicU64 = AtomicU64::new(u64::MAX);
+static MEMORY_USAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Cap the guest memory all the VMMs of this process may map, in bytes. Memory already mapped is
+/// not affected, only further allocations are refused. There is no cap by default.
+pub fn set_memory_budget(bytes: u64) {
+    MEMORY_BUDGET.store(bytes, Ordering::SeqCst);
+}
+
+/// Guest memory currently mapped by all the VMMs of this process, in bytes.
+pub fn current_usage() -> u64 {
+    MEMORY_USAGE.load(Ordering::SeqCst)
+}
+
+/// Guest memory accounted against the budget, given back when dropped.
+#[derive(Debug)]
+pub(crate) struct MemoryReservation(u64);
+
+// Account for `bytes` of guest memory against the budget before mapping them.
+fn reserve_memory(bytes: u64) -> Result<MemoryReservation> {
+    let budget = MEMORY_BUDGET.load(Ordering::SeqCst);
+    MEMORY_USAGE
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |usage| {
+            usage.checked_add(bytes).filter(|total| *total <= budget)
+        })
+        .map_err(|usage| Error::MemoryBudgetExceeded(bytes, budget.saturating_sub(usage)))?;
+
+    Ok(MemoryReservation(bytes))
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        MEMORY_USAGE.fetch_sub(self.0, Ordering::SeqCst);
+    }
+}
+
+/// Size of the huge pages backing the guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2 MiB pages.
+    Size2M,
+    /// 1 GiB pages.
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Page size, in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    fn mmap_flags(self) -> i32 {
+        libc::MAP_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MAP_HUGE_2MB,
+                HugePageSize::Size1G => libc::MAP_HUGE_1GB,
+            }
+    }
+}
+
+impl FromStr for HugePageSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "2M" => Ok(HugePageSize::Size2M),
+            "1G" => Ok(HugePageSize::Size1G),
+            _ => Err(format!("invalid huge page size {:?}, expected 2M or 1G", s)),
+        }
+    }
+}
+
+/// What the guest is told a memory range is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Usable RAM.
+    Ram,
+    /// Memory the guest must leave alone, e.g. the legacy BIOS area.
+    Reserved,
+    /// ACPI tables, reclaimable once parsed.
+    Acpi,
+    /// The 32-bit MMIO gap, kept for device memory. It is left out of the E820 table.
+    Mmio,
+}
+
+impl MemoryRegionKind {
+    /// Label the guest uses for this kind of range in `/proc/iomem`.
+    pub fn label(self) -> &'static str {
+        match self {
+            MemoryRegionKind::Ram => "System RAM",
+            MemoryRegionKind::Reserved => "Reserved",
+            MemoryRegionKind::Acpi => "ACPI Tables",
+            MemoryRegionKind::Mmio => "MMIO",
+        }
+    }
+}
+
+/// Description of a guest memory region, for external tools such as debuggers.
+#[derive(Clone, Debug)]
+pub struct MemoryRegionInfo {
+    /// Guest physical address of the region.
+    pub guest_base: GuestAddress,
+    /// Size of the region, in bytes.
+    pub size: u64,
+    /// Host virtual address the region is mapped at.
+    pub host_ptr: *mut u8,
+    /// What the guest may use the region for.
+    pub kind: MemoryRegionKind,
+}
+
+/// Guest physical memory map, as reported to the guest in the E820 table.
+///
+/// It is the host side ground truth to compare with the guest's `/proc/iomem`, which it prints
+/// in the same format.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    entries: Vec<(RangeInclusive, MemoryRegionKind)>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a range, keeping the entries sorted by address.
+    pub fn add(&mut self, range: RangeInclusive, kind: MemoryRegionKind) {
+        let index = self
+            .entries
+            .partition_point(|(entry, _)| entry.start() <= range.start());
+        self.entries.insert(index, (range, kind));
+    }
+
+    /// Ranges and what they are used for, in ascending address order.
+    pub fn entries(
+        &self,
+    ) -> impl Iterator<Item = (ops::RangeInclusive<u64>, MemoryRegionKind)> + '_ {
+        self.entries
+            .iter()
+            .map(|(range, kind)| (range.start()..=range.end(), *kind))
+    }
+
+    /// Ranges of the given kind, in ascending address order.
+    pub(crate) fn ranges(&self, kind: MemoryRegionKind) -> Vec<RangeInclusive> {
+        self.entries
+            .iter()
+            .filter(|(_, entry_kind)| *entry_kind == kind)
+            .map(|(range, _)| *range)
+            .collect()
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (range, kind) in self.entries() {
+            writeln!(
+                f,
+                "{:08x}-{:08x} : {}",
+                range.start(),
+                range.end(),
+                kind.label()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Split the mapped range `[start, end]` along the entries of `map`, in ascending address order.
+/// The parts no entry covers are reserved.
+pub(crate) fn split_by_kind(
+    start: u64,
+    end: u64,
+    map: &MemoryMap,
+) -> Vec<(ops::RangeInclusive<u64>, MemoryRegionKind)> {
+    let mut parts = Vec::new();
+    let mut addr = start;
+    for (range, kind) in map.entries.iter() {
+        if range.end() < addr || range.start() > end {
+            continue;
+        }
+        if range.start() > addr {
+            parts.push((addr..=range.start() - 1, MemoryRegionKind::Reserved));
+        }
+
+        let part_end = min(range.end(), end);
+        parts.push((max(addr, range.start())..=part_end, *kind));
+        match part_end.checked_add(1) {
+            Some(next) => addr = next,
+            None => return parts,
+        }
+    }
+    if addr <= end {
+        parts.push((addr..=end, MemoryRegionKind::Reserved));
+    }
+
+    parts
+}
+
+/// Start of the 32-bit MMIO gap, kept free of RAM below 4 GiB for device memory.
+pub(crate) const MMIO_GAP_START: u64 = 0xc000_0000;
+/// End of the 32-bit MMIO gap, where the RAM that doesn't fit below it continues.
+pub(crate) const MMIO_GAP_END: u64 = 0x1_0000_0000;
+
+/// Guest address and size of the RAM regions holding `mem_size` bytes: a single region starting
+/// at zero, split around the 32-bit MMIO gap when it doesn't fit below it.
+pub(crate) fn ram_regions(mem_size: u64) -> Vec<(GuestAddress, usize)> {
+    if mem_size <= MMIO_GAP_START {
+        return vec![(GuestAddress(0), mem_size as usize)];
+    }
+
+    vec![
+        (GuestAddress(0), MMIO_GAP_START as usize),
+        (
+            GuestAddress(MMIO_GAP_END),
+            (mem_size - MMIO_GAP_START) as usize,
+        ),
+    ]
+}
+
+/// Map the guest memory regions, after accounting for them against the memory budget. The
+/// returned reservation must be kept as long as the memory is mapped.
+///
+/// # Arguments
+///
+/// * `ranges` - guest address and size of each region.
+/// * `hugepages` - size of the huge pages backing the regions, if any.
+/// * `backing_file` - file to map the regions from with `MAP_SHARED`, one after the other,
+///                    instead of anonymous memory.
+pub(crate) fn build_guest_memory(
+    ranges: &[(GuestAddress, usize)],
+    hugepages: Option<HugePageSize>,
+    backing_file: Option<&File>,
+) -> Result<(GuestMemoryMmap, MemoryReservation)> {
+    let reservation = reserve_memory(ranges.iter().map(|(_, size)| *size as u64).sum())?;
+    let guest_memory = map_guest_memory(ranges, hugepages, backing_file)?;
+
+    Ok((guest_memory, reservation))
+}
+
+/// Map an anonymous region of `size` bytes at `base`, to add to the guest memory, after
+/// accounting for it against the memory budget. The returned reservation must be kept as long as
+/// the region is mapped.
+pub(crate) fn build_region(
+    base: GuestAddress,
+    size: usize,
+) -> Result<(GuestRegionMmap, MemoryReservation)> {
+    let reservation = reserve_memory(size as u64)?;
+    let mapping = MmapRegion::new(size).map_err(Error::AddMemory)?;
+    let region = GuestRegionMmap::new(mapping, base).map_err(Error::Memory)?;
+
+    Ok((region, reservation))
+}
+
+fn map_guest_memory(
+    ranges: &[(GuestAddress, usize)],
+    hugepages: Option<HugePageSize>,
+    backing_file: Option<&File>,
+) -> Result<GuestMemoryMmap> {
+    if let Some(file) = backing_file {
+        // The page size of a file mapping depends on the file, e.g. whether it lives on hugetlbfs.
+        if hugepages.is_some() {
+            return Err(Error::MemoryFileWithHugePages);
+        }
+        return file_backed_memory(ranges, file);
+    }
+
+    let hugepages = match hugepages {
+        Some(hugepages) => hugepages,
+        None => return GuestMemoryMmap::from_ranges(ranges).map_err(Error::Memory),
+    };
+
+    let mut regions = Vec::with_capacity(ranges.len());
+    for (base, size) in ranges {
+        if base.raw_value() % hugepages.bytes() != 0 || *size as u64 % hugepages.bytes() != 0 {
+            return Err(Error::HugePagesAlignment(hugepages));
+        }
+
+        // Unlike `from_ranges`, don't use MAP_NORESERVE: without it, mmap fails right away when
+        // not enough huge pages are available, instead of the guest getting a SIGBUS later.
+        let mapping = MmapRegion::build(
+            None,
+            *size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | hugepages.mmap_flags(),
+        )
+        .map_err(Error::HugePages)?;
+        regions.push(GuestRegionMmap::new(mapping, *base).map_err(Error::Memory)?);
+    }
+
+    GuestMemoryMmap::from_regions(regions).map_err(Error::Memory)
+}
+
+fn file_backed_memory(ranges: &[(GuestAddress, usize)], file: &File) -> Result<GuestMemoryMmap> {
+    let required: u64 = ranges.iter().map(|(_, size)| *size as u64).sum();
+    let file_size = file.metadata().map_err(Error::IO)?.len();
+    if file_size < required {
+        return Err(Error::MemoryFileTooSmall(file_size, required));
+    }
+
+    let mut file_ranges = Vec::with_capacity(ranges.len());
+    let mut offset = 0;
+    for (base, size) in ranges {
+        let file_offset = FileOffset::new(file.try_clone().map_err(Error::IO)?, offset);
+        file_ranges.push((*base, *size, Some(file_offset)));
+        offset += *size as u64;
+    }
+
+    GuestMemoryMmap::from_ranges_with_files(file_ranges).map_err(Error::Memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use vm_memory::Bytes;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // The memory budget and usage are shared by the whole process, so are the tests mapping memory.
+    static BUDGET_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn split_region_by_kind() {
+        let mut map = MemoryMap::new();
+        map.add(
+            RangeInclusive::new(0x10_0000, 0x1f_ffff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(0, 0x9_fbff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(0xe_0000, 0xf_ffff).unwrap(),
+            MemoryRegionKind::Acpi,
+        );
+
+        assert_eq!(
+            split_by_kind(0, 0x3f_ffff, &map),
+            vec![
+                (0..=0x9_fbff, MemoryRegionKind::Ram),
+                (0x9_fc00..=0xd_ffff, MemoryRegionKind::Reserved),
+                (0xe_0000..=0xf_ffff, MemoryRegionKind::Acpi),
+                (0x10_0000..=0x1f_ffff, MemoryRegionKind::Ram),
+                (0x20_0000..=0x3f_ffff, MemoryRegionKind::Reserved),
+            ]
+        );
+        assert_eq!(
+            split_by_kind(0x8_0000, 0xe_ffff, &map),
+            vec![
+                (0x8_0000..=0x9_fbff, MemoryRegionKind::Ram),
+                (0x9_fc00..=0xd_ffff, MemoryRegionKind::Reserved),
+                (0xe_0000..=0xe_ffff, MemoryRegionKind::Acpi),
+            ]
+        );
+        assert_eq!(
+            split_by_kind(0x50_0000, 0x5f_ffff, &map),
+            vec![(0x50_0000..=0x5f_ffff, MemoryRegionKind::Reserved)]
+        );
+    }
+
+    #[test]
+    fn ram_regions_skip_mmio_gap() {
+        assert_eq!(ram_regions(512 << 20), vec![(GuestAddress(0), 512 << 20)]);
+        assert_eq!(
+            ram_regions(MMIO_GAP_START),
+            vec![(GuestAddress(0), MMIO_GAP_START as usize)]
+        );
+        assert_eq!(
+            ram_regions(4 << 30),
+            vec![
+                (GuestAddress(0), MMIO_GAP_START as usize),
+                (GuestAddress(MMIO_GAP_END), 1 << 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_map_display() {
+        let mut map = MemoryMap::new();
+        map.add(
+            RangeInclusive::new(0x10_0000, 0x7ff_ffff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(0xe_0000, 0xf_ffff).unwrap(),
+            MemoryRegionKind::Acpi,
+        );
+        map.add(
+            RangeInclusive::new(0, 0x9_fbff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(MMIO_GAP_START, MMIO_GAP_END - 1).unwrap(),
+            MemoryRegionKind::Mmio,
+        );
+
+        assert_eq!(
+            map.to_string(),
+            "00000000-0009fbff : System RAM\n\
+             000e0000-000fffff : ACPI Tables\n\
+             00100000-07ffffff : System RAM\n\
+             c0000000-ffffffff : MMIO\n"
+        );
+        assert_eq!(map.ranges(MemoryRegionKind::Acpi).len(), 1);
+        assert!(map.ranges(MemoryRegionKind::Reserved).is_empty());
+    }
+
+    #[test]
+    fn parse_hugepage_size() {
+        assert_eq!("2M".parse(), Ok(HugePageSize::Size2M));
+        assert_eq!("1G".parse(), Ok(HugePageSize::Size1G));
+        assert!("4K".parse::<HugePageSize>().is_err());
+    }
+
+    #[test]
+    fn hugepages_require_aligned_regions() {
+        let _budget = BUDGET_LOCK.lock().unwrap();
+        let size_2m = HugePageSize::Size2M.bytes() as usize;
+
+        assert!(matches!(
+            build_guest_memory(
+                &[(GuestAddress(0), size_2m + 0x1000)],
+                Some(HugePageSize::Size2M),
+                None
+            ),
+            Err(Error::HugePagesAlignment(HugePageSize::Size2M))
+        ));
+        assert!(matches!(
+            build_guest_memory(
+                &[(GuestAddress(0x1000), size_2m)],
+                Some(HugePageSize::Size2M),
+                None
+            ),
+            Err(Error::HugePagesAlignment(HugePageSize::Size2M))
+        ));
+    }
+
+    #[test]
+    fn memory_budget() {
+        let _budget = BUDGET_LOCK.lock().unwrap();
+        let usage = current_usage();
+        set_memory_budget(usage + 0x3000);
+
+        let (region, reservation) = build_region(GuestAddress(0), 0x2000).unwrap();
+        assert_eq!(current_usage(), usage + 0x2000);
+        assert!(matches!(
+            build_guest_memory(&[(GuestAddress(0x10_0000), 0x2000)], None, None),
+            Err(Error::MemoryBudgetExceeded(0x2000, 0x1000))
+        ));
+
+        drop((region, reservation));
+        assert_eq!(current_usage(), usage);
+        assert!(build_guest_memory(&[(GuestAddress(0), 0x3000)], None, None).is_ok());
+
+        set_memory_budget(u64::MAX);
+    }
+
+    #[test]
+    fn file_backed_memory_is_shared() {
+        let _budget = BUDGET_LOCK.lock().unwrap();
+        let file = TempFile::new().unwrap().into_file();
+        let ranges = [(GuestAddress(0), 0x1000), (GuestAddress(0x10_0000), 0x1000)];
+
+        file.set_len(0x1fff).unwrap();
+        assert!(matches!(
+            build_guest_memory(&ranges, None, Some(&file)),
+            Err(Error::MemoryFileTooSmall(0x1fff, 0x2000))
+        ));
+
+        file.set_len(0x2000).unwrap();
+        let (guest_memory, _reservation) = build_guest_memory(&ranges, None, Some(&file)).unwrap();
+        guest_memory
+            .write_obj(0xaa55u16, GuestAddress(0x10_0000))
+            .unwrap();
+
+        // The second region starts right after the first one in the file.
+        let mut data = [0u8; 2];
+        std::os::unix::fs::FileExt::read_exact_at(&file, &mut data, 0x1000).unwrap();
+        assert_eq!(data, [0x55, 0xaa]);
+    }
+}
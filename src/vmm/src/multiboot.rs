@@ -0,0 +1,695 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+#![cfg(target_arch = "x86_64")]
+
+// Multiboot2 boot protocol: finding and parsing the kernel header, loading the kernel and
+// building the boot information structure.
+// See https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+
+use std::cmp::{max, min};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::memory::{MemoryMap, MemoryRegionKind};
+use crate::{Error, Result};
+
+// Header magic, and the part of the image the header must be in.
+pub(crate) const HEADER_MAGIC: u32 = 0xe852_50d6;
+pub(crate) const HEADER_SEARCH_LEN: u64 = 32768;
+// Header field: `architecture`. 32-bit protected mode i386.
+const ARCHITECTURE_I386: u32 = 0;
+// Size of the magic, architecture, header_length and checksum fields.
+const HEADER_FIELDS_SIZE: usize = 16;
+
+// Header tag types, and the flag telling the kernel boots even if the tag is ignored.
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+const HEADER_TAG_RELOCATABLE: u16 = 10;
+const HEADER_TAG_OPTIONAL: u16 = 1;
+// Console flags: the kernel needs a console described in the boot information.
+const CONSOLE_FLAGS_REQUIRED: u32 = 1;
+
+// Boot information tag types.
+const INFO_TAG_END: u32 = 0;
+const INFO_TAG_CMDLINE: u32 = 1;
+const INFO_TAG_BOOT_LOADER_NAME: u32 = 2;
+const INFO_TAG_MODULE: u32 = 3;
+const INFO_TAG_BASIC_MEMINFO: u32 = 4;
+const INFO_TAG_MMAP: u32 = 6;
+// Memory map entry size and types.
+const MMAP_ENTRY_SIZE: u32 = 24;
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_RESERVED: u32 = 2;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+// Basic memory information: lower memory starts at 0 and ends at 640 KiB at most, upper memory
+// starts at 1 MiB.
+const LOWER_MEMORY_END: u64 = 0xa_0000;
+const UPPER_MEMORY_START: u64 = 0x10_0000;
+
+/// Value of eax when entering the kernel, telling it it was loaded by a Multiboot2 bootloader.
+pub(crate) const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from(u32_at(bytes, offset)?) | (u64::from(u32_at(bytes, offset + 4)?) << 32))
+}
+
+/// Offset of the Multiboot2 header in `head`, the start of a kernel image, if it has one.
+///
+/// The header is 8-byte aligned, and its checksum makes the sum of its first four fields zero.
+/// Like GRUB, candidates with a bad checksum or for another architecture are skipped.
+pub(crate) fn find_header(head: &[u8]) -> Option<usize> {
+    (0..head.len()).step_by(8).find(|&offset| {
+        let field = |index: usize| u32_at(head, offset + index * 4);
+        let sum = (0..4).try_fold(0u32, |sum, index| Some(sum.wrapping_add(field(index)?)));
+
+        field(0) == Some(HEADER_MAGIC) && field(1) == Some(ARCHITECTURE_I386) && sum == Some(0)
+    })
+}
+
+// Header tag telling where to load the image, for kernels that aren't ELF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AddressTag {
+    header_addr: u32,
+    load_addr: u32,
+    load_end_addr: u32,
+    bss_end_addr: u32,
+}
+
+// The header tags acting on how the kernel is loaded.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct HeaderTags {
+    address: Option<AddressTag>,
+    entry_addr: Option<u32>,
+}
+
+// Parse the tags of the header at `offset` in `head`, refusing the ones the kernel can't boot
+// without and lumper doesn't support.
+fn parse_header_tags(head: &[u8], offset: usize) -> Result<HeaderTags> {
+    let header_length = u32_at(head, offset + 8).ok_or(Error::InvalidMultibootHeader)? as usize;
+    let end = offset
+        .checked_add(header_length)
+        .filter(|end| *end <= head.len())
+        .ok_or(Error::InvalidMultibootHeader)?;
+
+    let mut tags = HeaderTags::default();
+    let mut tag = offset + HEADER_FIELDS_SIZE;
+    loop {
+        let tag_type = u16_at(&head[..end], tag).ok_or(Error::InvalidMultibootHeader)?;
+        let flags = u16_at(&head[..end], tag + 2).ok_or(Error::InvalidMultibootHeader)?;
+        let size = u32_at(&head[..end], tag + 4).ok_or(Error::InvalidMultibootHeader)? as usize;
+        if size < 8 || tag + size > end {
+            return Err(Error::InvalidMultibootHeader);
+        }
+        // Fields past the tag size are missing, not part of the next tag.
+        let field = |field_offset: usize| {
+            u32_at(&head[tag..tag + size], field_offset).ok_or(Error::InvalidMultibootHeader)
+        };
+
+        match tag_type {
+            HEADER_TAG_END => return Ok(tags),
+            HEADER_TAG_INFORMATION_REQUEST => {
+                for request in (8..size).step_by(4) {
+                    let info_type = field(request)?;
+                    let provided = matches!(
+                        info_type,
+                        INFO_TAG_CMDLINE
+                            | INFO_TAG_BOOT_LOADER_NAME
+                            | INFO_TAG_MODULE
+                            | INFO_TAG_BASIC_MEMINFO
+                            | INFO_TAG_MMAP
+                    );
+                    if !provided && flags & HEADER_TAG_OPTIONAL == 0 {
+                        return Err(Error::UnsupportedMultibootInfo(info_type));
+                    }
+                }
+            }
+            HEADER_TAG_ADDRESS => {
+                tags.address = Some(AddressTag {
+                    header_addr: field(8)?,
+                    load_addr: field(12)?,
+                    load_end_addr: field(16)?,
+                    bss_end_addr: field(20)?,
+                });
+            }
+            HEADER_TAG_ENTRY_ADDRESS => tags.entry_addr = Some(field(8)?),
+            HEADER_TAG_CONSOLE_FLAGS => {
+                if field(8)? & CONSOLE_FLAGS_REQUIRED != 0 && flags & HEADER_TAG_OPTIONAL == 0 {
+                    return Err(Error::UnsupportedMultibootTag(tag_type));
+                }
+            }
+            // Modules are page aligned anyway, and relocatable kernels are loaded where they
+            // prefer to be.
+            HEADER_TAG_MODULE_ALIGN | HEADER_TAG_RELOCATABLE => {}
+            _ => {
+                if flags & HEADER_TAG_OPTIONAL == 0 {
+                    return Err(Error::UnsupportedMultibootTag(tag_type));
+                }
+            }
+        }
+
+        tag = (tag + size + 7) & !7;
+    }
+}
+
+// A part of the kernel image to copy to guest memory, followed by zeroes up to `mem_size`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Segment {
+    offset: u64,
+    file_size: u64,
+    addr: u64,
+    mem_size: u64,
+}
+
+/// Where a Multiboot2 kernel goes in guest memory, and where to enter it.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Kernel {
+    segments: Vec<Segment>,
+    /// Physical address of the 32-bit entry point.
+    pub entry: GuestAddress,
+}
+
+impl Kernel {
+    /// Guest physical range the kernel spans once loaded.
+    pub fn range(&self) -> ops::RangeInclusive<u64> {
+        let start = self.segments.iter().map(|segment| segment.addr).min();
+        let end = self
+            .segments
+            .iter()
+            .map(|segment| segment.addr + segment.mem_size - 1)
+            .max();
+
+        // Kernels have at least one segment, see `parse_kernel`.
+        start.unwrap_or(0)..=end.unwrap_or(0)
+    }
+
+    /// Copy the kernel from `kernel_image` to guest memory.
+    pub fn load<R: Read + Seek>(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        kernel_image: &mut R,
+    ) -> Result<()> {
+        for segment in self.segments.iter() {
+            kernel_image
+                .seek(SeekFrom::Start(segment.offset))
+                .map_err(Error::IO)?;
+            guest_memory
+                .read_exact_from(
+                    GuestAddress(segment.addr),
+                    kernel_image,
+                    segment.file_size as usize,
+                )
+                .map_err(Error::MultibootLoad)?;
+
+            let zeroes = vec![0u8; (segment.mem_size - segment.file_size) as usize];
+            guest_memory
+                .write_slice(&zeroes, GuestAddress(segment.addr + segment.file_size))
+                .map_err(Error::MultibootLoad)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find out where the Multiboot2 `kernel_image` is loaded and entered, from the address tags
+/// of its header or else from its ELF program headers.
+pub(crate) fn parse_kernel<R: Read + Seek>(kernel_image: &mut R) -> Result<Kernel> {
+    let mut head = Vec::new();
+    kernel_image.rewind().map_err(Error::IO)?;
+    kernel_image
+        .by_ref()
+        .take(HEADER_SEARCH_LEN)
+        .read_to_end(&mut head)
+        .map_err(Error::IO)?;
+    let image_len = kernel_image.seek(SeekFrom::End(0)).map_err(Error::IO)?;
+
+    let offset = find_header(&head).ok_or(Error::InvalidMultibootHeader)?;
+    let tags = parse_header_tags(&head, offset)?;
+
+    let (segments, entry) = match tags.address {
+        Some(address) => {
+            // Without ELF headers, nothing tells where to start the kernel.
+            let entry = tags.entry_addr.ok_or(Error::InvalidMultibootHeader)?;
+            (
+                vec![address_segment(address, offset as u64, image_len)?],
+                u64::from(entry),
+            )
+        }
+        None => {
+            kernel_image.rewind().map_err(Error::IO)?;
+            let (segments, entry) = read_elf(kernel_image).ok_or(Error::InvalidMultibootKernel)?;
+            (segments, tags.entry_addr.map_or(entry, u64::from))
+        }
+    };
+
+    let segments: Vec<Segment> = segments
+        .into_iter()
+        .filter(|segment| segment.mem_size > 0)
+        .collect();
+    if segments.is_empty() {
+        return Err(Error::InvalidMultibootKernel);
+    }
+    // The kernel is entered in 32-bit protected mode, without paging.
+    if entry > u64::from(u32::MAX) {
+        return Err(Error::MultibootEntryTooHigh(entry));
+    }
+
+    Ok(Kernel {
+        segments,
+        entry: GuestAddress(entry),
+    })
+}
+
+// The part of the image the address tag of the header at `header_offset` loads.
+fn address_segment(address: AddressTag, header_offset: u64, image_len: u64) -> Result<Segment> {
+    let header_addr = u64::from(address.header_addr);
+    let load_addr = u64::from(address.load_addr);
+    // The image is loaded from the offset matching `load_addr`, up to `load_end_addr` or the end
+    // of the image if zero, followed by the bss up to `bss_end_addr` if not zero.
+    let offset = header_addr
+        .checked_sub(load_addr)
+        .and_then(|distance| header_offset.checked_sub(distance))
+        .ok_or(Error::InvalidMultibootHeader)?;
+    let file_size = match address.load_end_addr {
+        0 => image_len.checked_sub(offset),
+        load_end_addr => u64::from(load_end_addr).checked_sub(load_addr),
+    }
+    .filter(|size| offset + size <= image_len)
+    .ok_or(Error::InvalidMultibootHeader)?;
+    let mem_size = match address.bss_end_addr {
+        0 => Some(file_size),
+        bss_end_addr => u64::from(bss_end_addr)
+            .checked_sub(load_addr)
+            .filter(|size| *size >= file_size),
+    }
+    .ok_or(Error::InvalidMultibootHeader)?;
+
+    Ok(Segment {
+        offset,
+        file_size,
+        addr: load_addr,
+        mem_size,
+    })
+}
+
+// The `PT_LOAD` segments and the physical entry point of an ELF32 or ELF64 image, loaded at the
+// physical addresses of its program headers like GRUB does.
+fn read_elf<R: Read + Seek>(kernel_image: &mut R) -> Option<(Vec<Segment>, u64)> {
+    const PT_LOAD: u32 = 1;
+
+    let mut ehdr = [0u8; 64];
+    kernel_image.read_exact(&mut ehdr).ok()?;
+    // ELF magic, little endian.
+    if ehdr[..4] != *b"\x7fELF" || ehdr[5] != 1 {
+        return None;
+    }
+    let elf64 = match ehdr[4] {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    // Offsets of the fields that differ between the two classes.
+    let (entry, phoff, phentsize, phnum) = if elf64 {
+        (u64_at(&ehdr, 24)?, u64_at(&ehdr, 32)?, 54, 56)
+    } else {
+        (
+            u64::from(u32_at(&ehdr, 24)?),
+            u64::from(u32_at(&ehdr, 28)?),
+            42,
+            44,
+        )
+    };
+    let phentsize = u16_at(&ehdr, phentsize)? as usize;
+    let phnum = u16_at(&ehdr, phnum)?;
+    if phentsize < if elf64 { 56 } else { 32 } {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let mut physical_entry = entry;
+    let mut phdr = vec![0u8; phentsize];
+    for index in 0..u64::from(phnum) {
+        let offset = phoff.checked_add(index * phentsize as u64)?;
+        kernel_image.seek(SeekFrom::Start(offset)).ok()?;
+        kernel_image.read_exact(&mut phdr).ok()?;
+
+        if u32_at(&phdr, 0)? != PT_LOAD {
+            continue;
+        }
+        let (p_offset, p_vaddr, p_paddr, p_filesz, p_memsz) = if elf64 {
+            (
+                u64_at(&phdr, 8)?,
+                u64_at(&phdr, 16)?,
+                u64_at(&phdr, 24)?,
+                u64_at(&phdr, 32)?,
+                u64_at(&phdr, 40)?,
+            )
+        } else {
+            (
+                u64::from(u32_at(&phdr, 4)?),
+                u64::from(u32_at(&phdr, 8)?),
+                u64::from(u32_at(&phdr, 12)?),
+                u64::from(u32_at(&phdr, 16)?),
+                u64::from(u32_at(&phdr, 20)?),
+            )
+        };
+        if p_filesz > p_memsz {
+            return None;
+        }
+        if p_paddr.checked_add(p_memsz).is_none() {
+            return None;
+        }
+
+        // The entry point may be a virtual address, translate it to where it gets loaded.
+        if p_vaddr <= entry && entry - p_vaddr < p_memsz {
+            physical_entry = entry - p_vaddr + p_paddr;
+        }
+        segments.push(Segment {
+            offset: p_offset,
+            file_size: p_filesz,
+            addr: p_paddr,
+            mem_size: p_memsz,
+        });
+    }
+
+    Some((segments, physical_entry))
+}
+
+// Append a boot information tag, padded to the 8-byte alignment of the next one.
+fn push_info_tag(info: &mut Vec<u8>, tag_type: u32, payload: &[u8]) {
+    info.extend_from_slice(&tag_type.to_le_bytes());
+    info.extend_from_slice(&(8 + payload.len() as u32).to_le_bytes());
+    info.extend_from_slice(payload);
+    info.resize((info.len() + 7) & !7, 0);
+}
+
+/// Build the Multiboot2 boot information structure.
+///
+/// # Arguments
+///
+/// * `cmdline` - kernel command line.
+/// * `memory_map` - memory map to report, the MMIO gap left out as in the E820 table.
+/// * `module` - guest physical range of the initial ramdisk, passed as a boot module, if any.
+pub(crate) fn build_info(
+    cmdline: &str,
+    memory_map: &MemoryMap,
+    module: Option<ops::Range<u32>>,
+) -> Vec<u8> {
+    // Total size and reserved fields, the size being filled in at the end.
+    let mut info = vec![0u8; 8];
+
+    push_info_tag(
+        &mut info,
+        INFO_TAG_CMDLINE,
+        format!("{}\0", cmdline).as_bytes(),
+    );
+    push_info_tag(&mut info, INFO_TAG_BOOT_LOADER_NAME, b"lumper\0");
+
+    if let Some(module) = module {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&module.start.to_le_bytes());
+        payload.extend_from_slice(&module.end.to_le_bytes());
+        // Empty module command line.
+        payload.push(0);
+        push_info_tag(&mut info, INFO_TAG_MODULE, &payload);
+    }
+
+    // Lower and upper memory in KiB, from the RAM ranges at 0 and 1 MiB.
+    let ram_from = |start: u64, limit: u64| {
+        memory_map
+            .entries()
+            .find(|(range, kind)| *kind == MemoryRegionKind::Ram && *range.start() == start)
+            .map_or(0, |(range, _)| {
+                let end = min(range.end().saturating_add(1), limit);
+                min((max(end, start) - start) >> 10, u64::from(u32::MAX)) as u32
+            })
+    };
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&ram_from(0, LOWER_MEMORY_END).to_le_bytes());
+    payload.extend_from_slice(&ram_from(UPPER_MEMORY_START, u64::MAX).to_le_bytes());
+    push_info_tag(&mut info, INFO_TAG_BASIC_MEMINFO, &payload);
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&MMAP_ENTRY_SIZE.to_le_bytes());
+    // Entry version.
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    for (range, kind) in memory_map.entries() {
+        let entry_type = match kind {
+            MemoryRegionKind::Ram => MMAP_AVAILABLE,
+            MemoryRegionKind::Reserved => MMAP_RESERVED,
+            MemoryRegionKind::Acpi => MMAP_ACPI_RECLAIMABLE,
+            MemoryRegionKind::Mmio => continue,
+        };
+        payload.extend_from_slice(&range.start().to_le_bytes());
+        payload.extend_from_slice(&(range.end() - range.start() + 1).to_le_bytes());
+        payload.extend_from_slice(&entry_type.to_le_bytes());
+        // Reserved.
+        payload.extend_from_slice(&0u32.to_le_bytes());
+    }
+    push_info_tag(&mut info, INFO_TAG_MMAP, &payload);
+
+    push_info_tag(&mut info, INFO_TAG_END, &[]);
+    let total_size = info.len() as u32;
+    info[..4].copy_from_slice(&total_size.to_le_bytes());
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use vm_allocator::RangeInclusive;
+
+    // Builds a Multiboot2 header with the given tags, the end tag included.
+    fn header(tags: &[(u16, u16, &[u32])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (tag_type, flags, fields) in tags.iter().chain([(HEADER_TAG_END, 0, &[][..])].iter()) {
+            body.extend_from_slice(&tag_type.to_le_bytes());
+            body.extend_from_slice(&flags.to_le_bytes());
+            body.extend_from_slice(&(8 + fields.len() as u32 * 4).to_le_bytes());
+            for field in fields.iter() {
+                body.extend_from_slice(&field.to_le_bytes());
+            }
+            body.resize((body.len() + 7) & !7, 0);
+        }
+
+        let header_length = (HEADER_FIELDS_SIZE + body.len()) as u32;
+        let checksum = 0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(header_length);
+        let mut header = Vec::new();
+        for field in [HEADER_MAGIC, ARCHITECTURE_I386, header_length, checksum] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        header.extend_from_slice(&body);
+        header
+    }
+
+    #[test]
+    fn find_header_skips_invalid_candidates() {
+        let valid = header(&[]);
+        let mut bad_checksum = valid.clone();
+        bad_checksum[12] ^= 1;
+        // Another architecture, with a checksum fixed accordingly.
+        let mut mips = valid.clone();
+        let checksum = u32_at(&mips, 12).unwrap().wrapping_sub(4);
+        mips[4..8].copy_from_slice(&4u32.to_le_bytes());
+        mips[12..16].copy_from_slice(&checksum.to_le_bytes());
+
+        let head = [bad_checksum, mips, valid.clone()].concat();
+        assert_eq!(find_header(&head), Some(2 * valid.len()));
+        assert_eq!(find_header(&head[..2 * valid.len()]), None);
+        // Only 8-byte aligned headers count.
+        assert_eq!(find_header(&[&[0u8; 4][..], &valid].concat()), None);
+    }
+
+    #[test]
+    fn parse_required_header_tags() {
+        let address = [0x10_0000, 0x10_0000, 0x10_2000, 0x10_3000];
+        let head = header(&[
+            (HEADER_TAG_ADDRESS, 0, &address[..]),
+            (HEADER_TAG_ENTRY_ADDRESS, 0, &[0x10_0040]),
+            (HEADER_TAG_INFORMATION_REQUEST, 0, &[INFO_TAG_MMAP]),
+        ]);
+        assert_eq!(
+            parse_header_tags(&head, 0).unwrap(),
+            HeaderTags {
+                address: Some(AddressTag {
+                    header_addr: 0x10_0000,
+                    load_addr: 0x10_0000,
+                    load_end_addr: 0x10_2000,
+                    bss_end_addr: 0x10_3000,
+                }),
+                entry_addr: Some(0x10_0040),
+            }
+        );
+
+        // A framebuffer, required or merely requested.
+        let head = header(&[(5, 0, &[1024, 768, 32])]);
+        assert!(matches!(
+            parse_header_tags(&head, 0),
+            Err(Error::UnsupportedMultibootTag(5))
+        ));
+        let head = header(&[(5, HEADER_TAG_OPTIONAL, &[1024, 768, 32])]);
+        assert_eq!(parse_header_tags(&head, 0).unwrap(), HeaderTags::default());
+
+        // The framebuffer information.
+        let head = header(&[(HEADER_TAG_INFORMATION_REQUEST, 0, &[INFO_TAG_CMDLINE, 8])]);
+        assert!(matches!(
+            parse_header_tags(&head, 0),
+            Err(Error::UnsupportedMultibootInfo(8))
+        ));
+
+        let truncated = header(&[(HEADER_TAG_ENTRY_ADDRESS, 0, &[0x10_0040])]);
+        assert!(matches!(
+            parse_header_tags(&truncated[..truncated.len() - 8], 0),
+            Err(Error::InvalidMultibootHeader)
+        ));
+    }
+
+    #[test]
+    fn parse_kernel_with_address_tag() {
+        // 0x100 bytes of code, then the header, loaded at 1 MiB with a page of bss.
+        let code = vec![0xf4u8; 0x100];
+        let head = header(&[
+            (HEADER_TAG_ADDRESS, 0, &[0x10_0100, 0x10_0000, 0, 0x10_2000]),
+            (HEADER_TAG_ENTRY_ADDRESS, 0, &[0x10_0000]),
+        ]);
+        let image = [code, head.clone()].concat();
+        let kernel = parse_kernel(&mut Cursor::new(&image)).unwrap();
+
+        assert_eq!(kernel.entry, GuestAddress(0x10_0000));
+        assert_eq!(
+            kernel.segments,
+            vec![Segment {
+                offset: 0,
+                file_size: image.len() as u64,
+                addr: 0x10_0000,
+                mem_size: 0x2000,
+            }]
+        );
+        assert_eq!(kernel.range(), 0x10_0000..=0x10_1fff);
+
+        // The entry point can't be guessed without ELF headers.
+        let head = header(&[(HEADER_TAG_ADDRESS, 0, &[0x10_0000, 0x10_0000, 0, 0])]);
+        assert!(matches!(
+            parse_kernel(&mut Cursor::new(head)),
+            Err(Error::InvalidMultibootHeader)
+        ));
+    }
+
+    #[test]
+    fn parse_elf32_kernel() {
+        // ELF32 header, one PT_LOAD program header linked at 3 GiB and loaded at 1 MiB, and the
+        // Multiboot2 header as the segment contents.
+        let head = header(&[]);
+        let mut image = b"\x7fELF".to_vec();
+        image.extend_from_slice(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend_from_slice(&2u16.to_le_bytes()); // e_type: ET_EXEC
+        image.extend_from_slice(&3u16.to_le_bytes()); // e_machine: EM_386
+        for field in [1u32, 0xc000_0010, 52, 0, 0] {
+            // e_version, e_entry, e_phoff, e_shoff, e_flags
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [52u16, 32, 1, 0, 0, 0] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        let filesz = head.len() as u32;
+        for field in [1u32, 88, 0xc000_0000, 0x10_0000, filesz, 0x1000, 5, 0x1000] {
+            // PT_LOAD, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags, p_align
+            image.extend_from_slice(&field.to_le_bytes());
+        }
+        // The header must be 8-byte aligned.
+        image.resize(88, 0);
+        image.extend_from_slice(&head);
+
+        let kernel = parse_kernel(&mut Cursor::new(image)).unwrap();
+        assert_eq!(kernel.entry, GuestAddress(0x10_0010));
+        assert_eq!(kernel.range(), 0x10_0000..=0x10_0fff);
+    }
+
+    #[test]
+    fn boot_information() {
+        let mut map = MemoryMap::new();
+        map.add(
+            RangeInclusive::new(0, 0x9_fbff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(0x9_fc00, 0xf_ffff).unwrap(),
+            MemoryRegionKind::Reserved,
+        );
+        map.add(
+            RangeInclusive::new(0x10_0000, 0x7ff_ffff).unwrap(),
+            MemoryRegionKind::Ram,
+        );
+        map.add(
+            RangeInclusive::new(0xc000_0000, 0xffff_ffff).unwrap(),
+            MemoryRegionKind::Mmio,
+        );
+
+        let info = build_info("console=ttyS0", &map, Some(0x20_0000..0x20_0800));
+        let field = |offset: usize| u32_at(&info, offset).unwrap();
+        assert_eq!(field(0) as usize, info.len());
+
+        // Walk the tags, checking their alignment.
+        let mut tags = Vec::new();
+        let mut offset = 8;
+        while field(offset) != INFO_TAG_END {
+            tags.push((field(offset), offset));
+            offset = (offset + field(offset + 4) as usize + 7) & !7;
+        }
+        assert_eq!(offset + 8, info.len());
+        let types: Vec<u32> = tags.iter().map(|(tag_type, _)| *tag_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                INFO_TAG_CMDLINE,
+                INFO_TAG_BOOT_LOADER_NAME,
+                INFO_TAG_MODULE,
+                INFO_TAG_BASIC_MEMINFO,
+                INFO_TAG_MMAP,
+            ]
+        );
+
+        let (_, cmdline) = tags[0];
+        assert_eq!(&info[cmdline + 8..cmdline + 22], b"console=ttyS0\0");
+        let (_, module) = tags[2];
+        assert_eq!(
+            (field(module + 8), field(module + 12)),
+            (0x20_0000, 0x20_0800)
+        );
+        let (_, meminfo) = tags[3];
+        assert_eq!((field(meminfo + 8), field(meminfo + 12)), (639, 0x1fc00));
+
+        // The MMIO gap isn't reported.
+        let (_, mmap) = tags[4];
+        assert_eq!(field(mmap + 4), 16 + 3 * MMAP_ENTRY_SIZE);
+        let entry = mmap + 16 + 2 * MMAP_ENTRY_SIZE as usize;
+        assert_eq!(
+            (
+                u64_at(&info, entry).unwrap(),
+                u64_at(&info, entry + 8).unwrap(),
+                field(entry + 16)
+            ),
+            (0x10_0000, 0x7f0_0000, MMAP_AVAILABLE)
+        );
+    }
+}
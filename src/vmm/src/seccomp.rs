@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use libc::c_long;
+
+// Grouped by what needs them, see `required_syscalls`.
+const REQUIRED_SYSCALLS: &[c_long] = &[
+    // vCPUs (KVM_RUN and friends), terminal and tap configuration.
+    libc::SYS_ioctl,
+    // Console, serial output, tap frames and eventfds.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_pwait,
+    // Locking of the shared devices.
+    libc::SYS_futex,
+    // Heap management.
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_munmap,
+    libc::SYS_madvise,
+    // Thread lifecycle, for the vCPU and GDB threads.
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // GDB stub connections. `TcpStream::try_clone` duplicates the socket with fcntl.
+    libc::SYS_accept4,
+    libc::SYS_fcntl,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_close,
+];
+
+/// System calls the VMM makes from `VMM::run` on, on x86_64.
+///
+/// Everything the configuration needs (opening `/dev/kvm`, the kernel, the tap...) happens before,
+/// so this is the allowlist for a seccomp filter installed right before running the guest. Any
+/// other system call can be treated as a compromise of the VMM.
+pub fn required_syscalls() -> &'static [c_long] {
+    REQUIRED_SYSCALLS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syscalls_are_unique() {
+        let mut syscalls = required_syscalls().to_vec();
+        syscalls.sort_unstable();
+        syscalls.dedup();
+
+        assert_eq!(syscalls.len(), required_syscalls().len());
+        assert!(syscalls.contains(&libc::SYS_ioctl));
+    }
+}
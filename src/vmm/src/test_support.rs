@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers shared by the unit tests.
+
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+/// Map anonymous guest memory with the given `(guest address, size)` regions.
+pub(crate) fn guest_memory_with(regions: &[(u64, usize)]) -> GuestMemoryMmap {
+    let ranges: Vec<(GuestAddress, usize)> = regions
+        .iter()
+        .map(|(addr, size)| (GuestAddress(*addr), *size))
+        .collect();
+
+    GuestMemoryMmap::from_ranges(&ranges).unwrap()
+}